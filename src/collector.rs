@@ -2,15 +2,250 @@ use core::cmp::Eq;
 use core::default::Default;
 use core::hash::Hash;
 use std::collections::HashMap;
+use std::time::Duration;
 
-#[derive(Default, Debug, Clone)]
+/// Number of power-of-two size classes tracked by a [`SizeHistogram`].
+pub const SIZE_CLASSES: usize = usize::BITS as usize;
+
+/// Log2 histogram of allocation sizes. Size class `k` counts allocations in the `[2^k, 2^(k+1))` byte range.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct SizeHistogram {
+    counts: [isize; SIZE_CLASSES],
+}
+
+impl SizeHistogram {
+    /// Returns the size class an allocation of `size` bytes falls into.
+    pub fn size_class(size: usize) -> usize {
+        (usize::BITS - 1 - size.max(1).leading_zeros()) as usize
+    }
+
+    /// Returns the smallest allocation size belonging to the size class.
+    pub fn lower_bound(class: usize) -> usize {
+        1 << class
+    }
+
+    pub fn record(&mut self, size: usize) {
+        self.counts[Self::size_class(size)] += 1;
+    }
+
+    pub fn count(&self, class: usize) -> isize {
+        self.counts[class]
+    }
+
+    /// Adds the counts of `other` to this histogram.
+    pub fn merge(&mut self, other: &Self) {
+        self.merge_scaled(other, 1)
+    }
+
+    pub(crate) fn merge_scaled(&mut self, other: &Self, factor: isize) {
+        for (count, other) in self.counts.iter_mut().zip(other.counts.iter()) {
+            *count += other * factor;
+        }
+    }
+
+    /// Iterates over the non-empty size classes, yielding the class and the number of allocations in it.
+    pub fn iter(&self) -> impl Iterator<Item = (usize, isize)> + '_ {
+        self.counts
+            .iter()
+            .enumerate()
+            .filter(|(_, count)| **count != 0)
+            .map(|(class, count)| (class, *count))
+    }
+}
+
+impl Default for SizeHistogram {
+    fn default() -> Self {
+        Self {
+            counts: [0; SIZE_CLASSES],
+        }
+    }
+}
+
+impl std::fmt::Debug for SizeHistogram {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_map()
+            .entries(
+                self.iter()
+                    .map(|(class, count)| (Self::lower_bound(class), count)),
+            )
+            .finish()
+    }
+}
+
+/// Log2 histogram of the lifetime of freed allocations. Class `k` covers lifetimes in the `[2^k, 2^(k+1))`
+/// nanoseconds range and keeps track of both the number of objects and the bytes freed.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct LifetimeHistogram {
+    objects: [isize; LIFETIME_CLASSES],
+    bytes: [isize; LIFETIME_CLASSES],
+}
+
+/// Number of power-of-two lifetime classes tracked by a [`LifetimeHistogram`].
+pub const LIFETIME_CLASSES: usize = u64::BITS as usize;
+
+impl LifetimeHistogram {
+    /// Returns the lifetime class an allocation that lived for `lifetime` falls into.
+    pub fn lifetime_class(lifetime: Duration) -> usize {
+        let nanos = u64::try_from(lifetime.as_nanos()).unwrap_or(u64::MAX);
+        (u64::BITS - 1 - nanos.max(1).leading_zeros()) as usize
+    }
+
+    /// Returns the shortest lifetime belonging to the lifetime class.
+    pub fn lower_bound(class: usize) -> Duration {
+        Duration::from_nanos(1 << class)
+    }
+
+    pub fn record(&mut self, bytes: isize, lifetime: Duration) {
+        let class = Self::lifetime_class(lifetime);
+        self.objects[class] += 1;
+        self.bytes[class] += bytes;
+    }
+
+    /// Adds the counts of `other` to this histogram.
+    pub fn merge(&mut self, other: &Self) {
+        self.merge_scaled(other, 1)
+    }
+
+    pub(crate) fn merge_scaled(&mut self, other: &Self, factor: isize) {
+        for class in 0..LIFETIME_CLASSES {
+            self.objects[class] += other.objects[class] * factor;
+            self.bytes[class] += other.bytes[class] * factor;
+        }
+    }
+
+    /// Iterates over the non-empty lifetime classes, yielding the class, the number of objects and bytes freed.
+    pub fn iter(&self) -> impl Iterator<Item = (usize, isize, isize)> + '_ {
+        (0..LIFETIME_CLASSES)
+            .filter(|&class| self.objects[class] != 0)
+            .map(|class| (class, self.objects[class], self.bytes[class]))
+    }
+
+    /// Returns the number of objects and bytes freed before they reached `max_lifetime`.
+    /// The granularity is the one of the lifetime classes: a class counts only if it lies entirely below `max_lifetime`.
+    pub fn freed_within(&self, max_lifetime: Duration) -> (isize, isize) {
+        self.iter()
+            .filter(|(class, _, _)| {
+                class + 1 < LIFETIME_CLASSES && Self::lower_bound(class + 1) <= max_lifetime
+            })
+            .fold((0, 0), |(objects, bytes), (_, o, b)| {
+                (objects + o, bytes + b)
+            })
+    }
+}
+
+impl Default for LifetimeHistogram {
+    fn default() -> Self {
+        Self {
+            objects: [0; LIFETIME_CLASSES],
+            bytes: [0; LIFETIME_CLASSES],
+        }
+    }
+}
+
+impl std::fmt::Debug for LifetimeHistogram {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_map()
+            .entries(
+                self.iter()
+                    .map(|(class, objects, bytes)| (Self::lower_bound(class), (objects, bytes))),
+            )
+            .finish()
+    }
+}
+
+/// The threads that made the sampled allocations of a call site, see [`current_thread_id`](crate::current_thread_id).
+/// Only a single thread is remembered, so that call sites don't grow with the number of threads.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SampleThreads {
+    #[default]
+    None,
+    One(u64),
+    Many,
+}
+
+impl SampleThreads {
+    fn add(&mut self, thread_id: u64) {
+        *self = match *self {
+            Self::None => Self::One(thread_id),
+            Self::One(id) if id == thread_id => Self::One(id),
+            _ => Self::Many,
+        }
+    }
+
+    fn merge(&mut self, other: Self) {
+        match other {
+            Self::None => {}
+            Self::One(id) => self.add(id),
+            Self::Many => *self = Self::Many,
+        }
+    }
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
 pub struct MemProfileRecord {
     pub alloc_bytes: isize,
     pub alloc_objects: isize,
+    pub alloc_sizes: SizeHistogram,
+    // bytes requested by the caller; alloc_bytes also includes the rounding up to the allocator size class.
+    pub requested_bytes: isize,
+    // threads that made the sampled allocations; they are not subtracted by a diff.
+    pub threads: SampleThreads,
     #[cfg(feature = "measure_free")]
     pub free_bytes: isize,
     #[cfg(feature = "measure_free")]
     pub free_objects: isize,
+    // lifetimes of the sampled allocations made at this site which have since been freed.
+    #[cfg(feature = "measure_free")]
+    pub lifetimes: LifetimeHistogram,
+    // sampled allocations made at this site which have later been resized by realloc.
+    #[cfg(feature = "measure_free")]
+    pub realloc_chains: isize,
+    #[cfg(feature = "measure_free")]
+    pub realloc_grow_steps: isize,
+    // bytes copied by reallocs that had to move the block.
+    #[cfg(feature = "measure_free")]
+    pub realloc_copied_bytes: isize,
+    // sum of the latest size of each realloc chain.
+    #[cfg(feature = "measure_free")]
+    pub realloc_final_bytes: isize,
+    // sampled allocations made at this site which have been freed by another thread.
+    #[cfg(feature = "measure_free")]
+    pub remote_free_objects: isize,
+    #[cfg(feature = "measure_free")]
+    pub remote_free_bytes: isize,
+}
+
+impl MemProfileRecord {
+    /// Bytes wasted to allocator size class rounding, i.e. usable minus requested bytes.
+    pub fn slack_bytes(&self) -> isize {
+        self.alloc_bytes - self.requested_bytes
+    }
+
+    /// Adds the counters of `other`, recorded for the same call site, to this record.
+    pub fn merge(&mut self, other: &Self) {
+        self.merge_scaled(other, 1)
+    }
+
+    // Adds the counters of `other` multiplied by `factor`: -1 subtracts them.
+    pub(crate) fn merge_scaled(&mut self, other: &Self, factor: isize) {
+        self.alloc_bytes += other.alloc_bytes * factor;
+        self.alloc_objects += other.alloc_objects * factor;
+        self.alloc_sizes.merge_scaled(&other.alloc_sizes, factor);
+        self.requested_bytes += other.requested_bytes * factor;
+        self.threads.merge(other.threads);
+        #[cfg(feature = "measure_free")]
+        {
+            self.free_bytes += other.free_bytes * factor;
+            self.free_objects += other.free_objects * factor;
+            self.lifetimes.merge_scaled(&other.lifetimes, factor);
+            self.realloc_chains += other.realloc_chains * factor;
+            self.realloc_grow_steps += other.realloc_grow_steps * factor;
+            self.realloc_copied_bytes += other.realloc_copied_bytes * factor;
+            self.realloc_final_bytes += other.realloc_final_bytes * factor;
+            self.remote_free_objects += other.remote_free_objects * factor;
+            self.remote_free_bytes += other.remote_free_bytes * factor;
+        }
+    }
 }
 
 #[cfg(feature = "measure_free")]
@@ -24,6 +259,50 @@ impl MemProfileRecord {
     }
 }
 
+/// A per call site value of a [`MemProfileRecord`], e.g. to choose what a folded stacks report or a flamegraph shows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Metric {
+    AllocObjects,
+    #[default]
+    AllocBytes,
+    #[cfg(feature = "measure_free")]
+    FreeObjects,
+    #[cfg(feature = "measure_free")]
+    FreeBytes,
+    #[cfg(feature = "measure_free")]
+    InUseObjects,
+    #[cfg(feature = "measure_free")]
+    InUseBytes,
+}
+
+impl Metric {
+    pub fn value(&self, rec: &MemProfileRecord) -> isize {
+        match self {
+            Self::AllocObjects => rec.alloc_objects,
+            Self::AllocBytes => rec.alloc_bytes,
+            #[cfg(feature = "measure_free")]
+            Self::FreeObjects => rec.free_objects,
+            #[cfg(feature = "measure_free")]
+            Self::FreeBytes => rec.free_bytes,
+            #[cfg(feature = "measure_free")]
+            Self::InUseObjects => rec.in_use_objects(),
+            #[cfg(feature = "measure_free")]
+            Self::InUseBytes => rec.in_use_bytes(),
+        }
+    }
+
+    /// Tells whether the metric counts objects rather than bytes.
+    pub fn is_objects(&self) -> bool {
+        match self {
+            Self::AllocObjects => true,
+            #[cfg(feature = "measure_free")]
+            Self::FreeObjects | Self::InUseObjects => true,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct Collector<K: Hash + Eq + 'static> {
     map: HashMap<K, MemProfileRecord>,
 }
@@ -35,12 +314,25 @@ impl<K: Hash + Eq + 'static> Collector<K> {
         }
     }
 
-    pub fn record(&mut self, key: K, bytes: isize) {
+    /// Records an allocation of `bytes` usable bytes by `thread_id`, of which `requested` were asked for by the caller,
+    /// resulting in a block of `block_size` bytes: for a realloc, `bytes` is only the size delta. The size histogram
+    /// counts the block sizes. Negative `bytes` record a free, `block_size`, `requested` and `thread_id` are ignored.
+    pub fn record(
+        &mut self,
+        key: K,
+        bytes: isize,
+        block_size: usize,
+        requested: isize,
+        thread_id: u64,
+    ) {
         let rec = self.map.entry(key).or_insert_with(Default::default);
         match bytes.cmp(&0) {
             std::cmp::Ordering::Greater => {
                 rec.alloc_bytes += bytes;
                 rec.alloc_objects += 1;
+                rec.requested_bytes += requested;
+                rec.alloc_sizes.record(block_size);
+                rec.threads.add(thread_id);
             }
             #[cfg(feature = "measure_free")]
             std::cmp::Ordering::Less => {
@@ -58,6 +350,43 @@ impl<K: Hash + Eq + 'static> Collector<K> {
     }
 }
 
+#[cfg(feature = "measure_free")]
+impl<K: Hash + Eq + 'static> Collector<K> {
+    /// Records that an allocation of `bytes` made at `key` has been freed after `lifetime`.
+    pub fn record_lifetime(&mut self, key: K, bytes: isize, lifetime: Duration) {
+        let rec = self.map.entry(key).or_insert_with(Default::default);
+        rec.lifetimes.record(bytes, lifetime);
+    }
+
+    /// Records that an allocation of `bytes` made at `key` has been freed by a thread other than the allocating one.
+    pub fn record_remote_free(&mut self, key: K, bytes: isize) {
+        let rec = self.map.entry(key).or_insert_with(Default::default);
+        rec.remote_free_objects += 1;
+        rec.remote_free_bytes += bytes;
+    }
+
+    /// Records a realloc of an allocation made at `key`. `first` tells whether it's the first realloc of the block,
+    /// `copied` how many bytes had to be moved and `size_delta` by how much the latest size of the chain changed.
+    pub fn record_realloc(
+        &mut self,
+        key: K,
+        grew: bool,
+        first: bool,
+        copied: isize,
+        size_delta: isize,
+    ) {
+        let rec = self.map.entry(key).or_insert_with(Default::default);
+        if first {
+            rec.realloc_chains += 1;
+        }
+        if grew {
+            rec.realloc_grow_steps += 1;
+        }
+        rec.realloc_copied_bytes += copied;
+        rec.realloc_final_bytes += size_delta;
+    }
+}
+
 impl<K: Hash + Eq + 'static> IntoIterator for Collector<K> {
     type Item = (K, MemProfileRecord);
     type IntoIter = std::collections::hash_map::IntoIter<K, MemProfileRecord>;
@@ -72,3 +401,45 @@ impl<K: Hash + Eq + 'static> Default for Collector<K> {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_size_histogram() {
+        let mut collector = Collector::new();
+        collector.record("a", 1, 1, 1, 1);
+        collector.record("a", 24, 24, 20, 1);
+        collector.record("a", 31, 31, 31, 1);
+        collector.record("a", 1 << 20, 1 << 20, 1 << 20, 1);
+        // a realloc growing a block to 1MiB + 4KiB.
+        collector.record("a", 4096, (1 << 20) + 4096, 4096, 1);
+
+        let (_, rec) = collector.into_iter().next().unwrap();
+        assert_eq!(rec.alloc_objects, 5);
+        assert_eq!(rec.slack_bytes(), 4);
+        assert_eq!(
+            rec.alloc_sizes.iter().collect::<Vec<_>>(),
+            vec![(0, 1), (4, 2), (20, 2)]
+        );
+        assert_eq!(SizeHistogram::lower_bound(20), 1 << 20);
+    }
+
+    #[cfg(feature = "measure_free")]
+    #[test]
+    fn test_lifetime_histogram() {
+        let mut collector = Collector::new();
+        collector.record_lifetime("a", 10, Duration::from_nanos(500));
+        collector.record_lifetime("a", 20, Duration::from_micros(3));
+        collector.record_lifetime("a", 40, Duration::from_secs(1));
+
+        let (_, rec) = collector.into_iter().next().unwrap();
+        assert_eq!(rec.lifetimes.iter().count(), 3);
+        assert_eq!(
+            rec.lifetimes.freed_within(Duration::from_micros(10)),
+            (2, 30)
+        );
+        assert_eq!(rec.lifetimes.freed_within(Duration::from_secs(10)), (3, 70));
+    }
+}
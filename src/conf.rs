@@ -0,0 +1,155 @@
+//! Configuration from the `HEAPPY_CONF` environment variable, read when the process starts so that profiling can be
+//! turned on without code changes, similar to jemalloc's `MALLOC_CONF`. For example:
+//!
+//! ```text
+//! HEAPPY_CONF="period=524288,free=1,dump_dir=/tmp/heap,interval=60s,on_exit=1"
+//! ```
+//!
+//! * `period`: sampling period in bytes.
+//! * `free`: whether to track frees, with the `measure_free` feature (on by default).
+//! * `dump_dir`: directory to dump profiles to, see [`crate::dumper`]. Defaults to the current directory when only
+//!   `interval` or `on_exit` are given.
+//! * `interval`: interval between dumps, e.g. `500ms`, `60s`, `5m`, `1h` or a number of seconds. Defaults to 60s when
+//!   `dump_dir` is given, otherwise no periodic dump is made.
+//! * `on_exit`: dump when the process exits.
+//!
+//! The profiler started this way runs until the process exits.
+
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::dumper::DumperBuilder;
+use crate::HeapProfilerGuardBuilder;
+
+const ENV_VAR: &str = "HEAPPY_CONF";
+
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Conf {
+    period: Option<usize>,
+    free: Option<bool>,
+    dump_dir: Option<PathBuf>,
+    interval: Option<Duration>,
+    on_exit: bool,
+}
+
+fn parse(conf: &str) -> Result<Conf, String> {
+    let mut parsed = Conf::default();
+    for option in conf.split(',').map(str::trim).filter(|o| !o.is_empty()) {
+        let (key, value) = option
+            .split_once('=')
+            .ok_or_else(|| format!("missing value for {:?}", option))?;
+        let invalid = || format!("invalid value for {}: {:?}", key, value);
+        match key {
+            "period" => parsed.period = Some(value.parse().map_err(|_| invalid())?),
+            "free" => parsed.free = Some(parse_bool(value).ok_or_else(invalid)?),
+            "dump_dir" => parsed.dump_dir = Some(PathBuf::from(value)),
+            "interval" => parsed.interval = Some(parse_duration(value).ok_or_else(invalid)?),
+            "on_exit" => parsed.on_exit = parse_bool(value).ok_or_else(invalid)?,
+            _ => return Err(format!("unknown option {:?}", key)),
+        }
+    }
+    Ok(parsed)
+}
+
+fn parse_bool(value: &str) -> Option<bool> {
+    match value {
+        "1" | "true" => Some(true),
+        "0" | "false" => Some(false),
+        _ => None,
+    }
+}
+
+fn parse_duration(value: &str) -> Option<Duration> {
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let number: u64 = number.parse().ok()?;
+    match unit {
+        "ms" => Some(Duration::from_millis(number)),
+        "" | "s" => Some(Duration::from_secs(number)),
+        "m" => number.checked_mul(60).map(Duration::from_secs),
+        "h" => number.checked_mul(3600).map(Duration::from_secs),
+        _ => None,
+    }
+}
+
+fn start(conf: Conf) -> crate::Result<()> {
+    let mut profiler = HeapProfilerGuardBuilder::default();
+    if let Some(period) = conf.period {
+        profiler = profiler.period(period);
+    }
+    match conf.free {
+        #[cfg(feature = "measure_free")]
+        Some(free) => profiler = profiler.track_frees(free),
+        #[cfg(not(feature = "measure_free"))]
+        Some(true) => eprintln!("heappy: free=1 requires the measure_free feature, ignoring it"),
+        _ => {}
+    }
+
+    if conf.dump_dir.is_none() && conf.interval.is_none() && !conf.on_exit {
+        std::mem::forget(profiler.build()?);
+        return Ok(());
+    }
+    let mut dumper = DumperBuilder::new(conf.dump_dir.clone().unwrap_or_else(|| ".".into()))
+        .profiler(profiler)
+        .on_exit(conf.on_exit);
+    match (conf.interval, conf.dump_dir) {
+        (Some(interval), _) => dumper = dumper.interval(interval),
+        (None, None) => dumper = dumper.interval(Duration::ZERO),
+        (None, Some(_)) => {}
+    }
+    std::mem::forget(dumper.build()?);
+    Ok(())
+}
+
+// Starts the profiler as configured by `HEAPPY_CONF`, if set. Runs before `main`, see `hook::INIT`.
+#[cfg_attr(not(feature = "enable_heap_profiler"), allow(dead_code))]
+pub(crate) extern "C" fn init() {
+    let Ok(conf) = std::env::var(ENV_VAR) else {
+        return;
+    };
+    let res = parse(&conf).and_then(|conf| start(conf).map_err(|err| err.to_string()));
+    if let Err(err) = res {
+        eprintln!("heappy: invalid {}: {}", ENV_VAR, err);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            parse("period=524288,free=1,dump_dir=/tmp/heap,interval=60s,on_exit=1"),
+            Ok(Conf {
+                period: Some(524288),
+                free: Some(true),
+                dump_dir: Some("/tmp/heap".into()),
+                interval: Some(Duration::from_secs(60)),
+                on_exit: true,
+            })
+        );
+        assert_eq!(parse(""), Ok(Conf::default()));
+        assert_eq!(
+            parse("interval=500ms").unwrap().interval,
+            Some(Duration::from_millis(500))
+        );
+        assert_eq!(
+            parse("interval=5m").unwrap().interval,
+            Some(Duration::from_secs(300))
+        );
+        assert_eq!(
+            parse("interval=10").unwrap().interval,
+            Some(Duration::from_secs(10))
+        );
+
+        assert!(parse("period=big").is_err());
+        assert!(parse("interval=1d").is_err());
+        assert!(parse("interval=18446744073709551615h").is_err());
+        assert!(parse("free=yes").is_err());
+        assert!(parse("period").is_err());
+        assert!(parse("colour=blue").is_err());
+    }
+}
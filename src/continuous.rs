@@ -0,0 +1,244 @@
+//! Continuous profiling: the profiler keeps running and its samples are cut into fixed length windows, of which the
+//! most recent ones are kept in memory.
+//!
+//! The windows are the differences between consecutive snapshots of the profiler, which keeps accumulating samples
+//! for the whole session: the other consumers of the running profiler, e.g. the dumps and the HTTP endpoints, keep
+//! seeing everything sampled since it was started.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, SystemTime};
+
+use crate::{HeapProfilerGuard, HeapProfilerGuardBuilder, HeapReport, Result};
+
+const DEFAULT_WINDOW: Duration = Duration::from_secs(60);
+const DEFAULT_WINDOWS: usize = 15;
+
+type Callback = Box<dyn Fn(&HeapWindow) + Send>;
+
+/// The allocations sampled during one window of a [`ContinuousProfiler`].
+#[derive(Debug, Clone)]
+pub struct HeapWindow {
+    pub start: SystemTime,
+    pub end: SystemTime,
+    /// A delta report: it only covers the allocations and frees which happened during the window.
+    pub report: HeapReport,
+}
+
+/// Configures and starts a [`ContinuousProfiler`].
+pub struct ContinuousProfilerBuilder {
+    profiler: HeapProfilerGuardBuilder,
+    window: Duration,
+    windows: usize,
+    on_window: Option<Callback>,
+}
+
+impl Default for ContinuousProfilerBuilder {
+    fn default() -> Self {
+        Self {
+            profiler: HeapProfilerGuardBuilder::default(),
+            window: DEFAULT_WINDOW,
+            windows: DEFAULT_WINDOWS,
+            on_window: None,
+        }
+    }
+}
+
+impl fmt::Debug for ContinuousProfilerBuilder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ContinuousProfilerBuilder")
+            .field("profiler", &self.profiler)
+            .field("window", &self.window)
+            .field("windows", &self.windows)
+            .finish()
+    }
+}
+
+impl ContinuousProfilerBuilder {
+    /// Sets the options of the underlying heap profiler session.
+    pub fn profiler(mut self, profiler: HeapProfilerGuardBuilder) -> Self {
+        self.profiler = profiler;
+        self
+    }
+
+    /// Sets the length of a window.
+    pub fn window(mut self, window: Duration) -> Self {
+        self.window = window;
+        self
+    }
+
+    /// Sets how many of the most recent windows are kept in memory.
+    pub fn windows(mut self, windows: usize) -> Self {
+        self.windows = windows.max(1);
+        self
+    }
+
+    /// Invokes `callback` on the profiling thread every time a window is completed.
+    pub fn on_window<F>(mut self, callback: F) -> Self
+    where
+        F: Fn(&HeapWindow) + Send + 'static,
+    {
+        self.on_window = Some(Box::new(callback));
+        self
+    }
+
+    pub fn build(self) -> Result<ContinuousProfiler> {
+        let guard = self.profiler.build()?;
+        let base = HeapReport::snapshot();
+        let shared = Arc::new(Shared {
+            windows: Mutex::new(VecDeque::new()),
+            stopped: Mutex::new(false),
+            stop: Condvar::new(),
+        });
+
+        let thread = {
+            let shared = shared.clone();
+            std::thread::Builder::new()
+                .name("heappy-continuous".to_string())
+                .spawn(move || shared.run(base, self.window, self.windows, self.on_window))
+                .expect("cannot spawn the continuous profiling thread")
+        };
+
+        Ok(ContinuousProfiler {
+            shared,
+            thread: Some(thread),
+            _guard: guard,
+        })
+    }
+}
+
+/// RAII structure keeping the heap profiler running until dropped, while a background thread takes a delta report
+/// every window. Only one heap profiler can run at a time.
+pub struct ContinuousProfiler {
+    shared: Arc<Shared>,
+    thread: Option<JoinHandle<()>>,
+    // declared last: the profiling thread must be stopped before the profiler.
+    _guard: HeapProfilerGuard,
+}
+
+impl ContinuousProfiler {
+    /// Returns a copy of the windows kept in memory, oldest first.
+    pub fn windows(&self) -> Vec<HeapWindow> {
+        lock(&self.shared.windows).iter().cloned().collect()
+    }
+
+    /// Returns a report merging the windows which ended within the last `duration`. The current, incomplete, window
+    /// isn't included.
+    pub fn last(&self, duration: Duration) -> HeapReport {
+        let since = SystemTime::now()
+            .checked_sub(duration)
+            .unwrap_or(SystemTime::UNIX_EPOCH);
+        let windows = lock(&self.shared.windows);
+        HeapReport::merge(
+            windows
+                .iter()
+                .filter(|window| window.end >= since)
+                .map(|window| &window.report),
+        )
+    }
+}
+
+impl Drop for ContinuousProfiler {
+    fn drop(&mut self) {
+        *lock(&self.shared.stopped) = true;
+        self.shared.stop.notify_all();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+struct Shared {
+    windows: Mutex<VecDeque<HeapWindow>>,
+    stopped: Mutex<bool>,
+    stop: Condvar,
+}
+
+impl Shared {
+    // Cuts a window every `window`, starting from the `base` snapshot taken when the profiler was started.
+    fn run(
+        &self,
+        mut base: HeapReport,
+        window: Duration,
+        windows: usize,
+        on_window: Option<Callback>,
+    ) {
+        let mut start = SystemTime::now();
+        loop {
+            let stopped = self
+                .stop
+                .wait_timeout_while(lock(&self.stopped), window, |stopped| !*stopped)
+                .map_or(true, |(stopped, _)| *stopped);
+            if stopped {
+                return;
+            }
+
+            let snapshot = HeapReport::snapshot();
+            let report = snapshot.diff(&base);
+            base = snapshot;
+            let end = SystemTime::now();
+            let completed = HeapWindow { start, end, report };
+            start = end;
+
+            if let Some(callback) = on_window.as_ref() {
+                callback(&completed);
+            }
+            let mut ring = lock(&self.windows);
+            if ring.len() == windows {
+                ring.pop_front();
+            }
+            ring.push_back(completed);
+        }
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+#[cfg(all(test, feature = "enable_heap_profiler"))]
+mod test {
+    use super::*;
+    use crate::profiler::test::{serial, test_profiler};
+
+    fn wait_for<F: Fn() -> bool>(condition: F) {
+        let deadline = std::time::Instant::now() + Duration::from_secs(10);
+        while !condition() {
+            assert!(std::time::Instant::now() < deadline, "timed out");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn test_continuous() {
+        let _serial = serial();
+        let profiler = ContinuousProfilerBuilder::default()
+            .profiler(test_profiler())
+            .window(Duration::from_millis(20))
+            .windows(3)
+            .build()
+            .unwrap();
+
+        std::hint::black_box(vec![0u8; 1 << 20]);
+        wait_for(|| !profiler.windows().is_empty());
+        let (_, bytes) = profiler.windows()[0].report.allocated();
+        assert!(bytes >= 1 << 20);
+        let (_, bytes) = profiler.last(Duration::from_secs(3600)).allocated();
+        assert!(bytes >= 1 << 20);
+
+        // the windows are deltas, and only the latest ones are kept.
+        wait_for(|| profiler.windows().len() == 3);
+        std::thread::sleep(Duration::from_millis(100));
+        let windows = profiler.windows();
+        assert_eq!(windows.len(), 3);
+        assert!(windows
+            .iter()
+            .all(|window| window.report.allocated().1 < 1 << 20));
+        assert!(windows.windows(2).all(|w| w[0].end == w[1].start));
+
+        // the running profiler keeps accumulating samples across windows.
+        assert!(HeapReport::snapshot().allocated().1 >= 1 << 20);
+    }
+}
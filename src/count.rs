@@ -0,0 +1,136 @@
+//! Exact allocation counting for tests and benchmarks.
+
+use std::fmt;
+use std::time::Duration;
+
+use crate::{HeapProfilerGuardBuilder, HeapReport};
+
+/// The allocations performed by a closure run with [`count_allocations`].
+#[derive(Debug)]
+pub struct AllocationCount {
+    pub objects: isize,
+    pub bytes: isize,
+    /// Where the allocations were made.
+    pub report: HeapReport,
+}
+
+impl AllocationCount {
+    /// Panics, listing the allocating stacks, if more than `max_bytes` or `max_objects` have been allocated.
+    pub fn assert_within(&self, max_bytes: Option<isize>, max_objects: Option<isize>) {
+        let bytes_exceeded = max_bytes.map_or(false, |max| self.bytes > max);
+        let objects_exceeded = max_objects.map_or(false, |max| self.objects > max);
+        if bytes_exceeded || objects_exceeded {
+            panic!(
+                "allocation budget exceeded (max_bytes: {}, max_objects: {}): {}",
+                max_bytes.map_or("-".to_string(), |max| max.to_string()),
+                max_objects.map_or("-".to_string(), |max| max.to_string()),
+                self
+            );
+        }
+    }
+}
+
+impl fmt::Display for AllocationCount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{} bytes in {} objects", self.bytes, self.objects)?;
+        let mut stacks = vec![];
+        self.report
+            .write_size_histograms(&mut stacks)
+            .map_err(|_| fmt::Error)?;
+        f.write_str(&String::from_utf8_lossy(&stacks))
+    }
+}
+
+// How long `count_allocations` waits for another profiler to be stopped.
+const WAIT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Runs `f` while counting every allocation it performs on the current thread.
+///
+/// Only one heap profiler can run at a time: this waits for any other profiler to be stopped first, which also
+/// serializes concurrently running tests. Panics if no profiler could be started within a minute, e.g. because one
+/// has been started for the whole life of the process.
+pub fn count_allocations<F, R>(f: F) -> (R, AllocationCount)
+where
+    F: FnOnce() -> R,
+{
+    let guard = HeapProfilerGuardBuilder::default()
+        .period(1)
+        .current_thread_only()
+        .build_waiting(WAIT_TIMEOUT)
+        .unwrap_or_else(|err| panic!("cannot count allocations: {}", err));
+    let res = f();
+    let report = guard.report();
+
+    let (objects, bytes) = report.allocated();
+    (
+        res,
+        AllocationCount {
+            objects,
+            bytes,
+            report,
+        },
+    )
+}
+
+/// Runs a block and panics, listing the allocating stacks, if it allocates more than the given budget on the
+/// current thread. Evaluates to the value of the block.
+///
+/// ```ignore
+/// let sum = heappy::assert_allocations!(max_bytes = 0, max_objects = 0, {
+///     values.iter().sum::<u64>()
+/// });
+/// ```
+#[macro_export]
+macro_rules! assert_allocations {
+    (max_bytes = $max_bytes:expr, max_objects = $max_objects:expr, $body:block) => {{
+        let (res, count) = $crate::count_allocations(|| $body);
+        count.assert_within(Some($max_bytes), Some($max_objects));
+        res
+    }};
+    (max_objects = $max_objects:expr, max_bytes = $max_bytes:expr, $body:block) => {
+        $crate::assert_allocations!(max_bytes = $max_bytes, max_objects = $max_objects, $body)
+    };
+    (max_bytes = $max_bytes:expr, $body:block) => {{
+        let (res, count) = $crate::count_allocations(|| $body);
+        count.assert_within(Some($max_bytes), None);
+        res
+    }};
+    (max_objects = $max_objects:expr, $body:block) => {{
+        let (res, count) = $crate::count_allocations(|| $body);
+        count.assert_within(None, Some($max_objects));
+        res
+    }};
+}
+
+#[cfg(all(test, feature = "enable_heap_profiler"))]
+mod test {
+    use super::*;
+    use crate::profiler::test::serial;
+
+    #[test]
+    fn test_count_allocations() {
+        let _serial = serial();
+        let (len, count) = count_allocations(|| std::hint::black_box(vec![0u8; 100]).len());
+        assert_eq!(len, 100);
+        assert_eq!(count.objects, 1);
+        assert!(count.bytes >= 100);
+
+        let sum =
+            crate::assert_allocations!(max_bytes = 0, max_objects = 0, { (0..10u64).sum::<u64>() });
+        assert_eq!(sum, 45);
+    }
+
+    #[test]
+    fn test_assert_allocations_fails() {
+        let _serial = serial();
+        let res = std::panic::catch_unwind(|| {
+            crate::assert_allocations!(max_objects = 1, {
+                std::hint::black_box(vec![1u8; 10]);
+                std::hint::black_box(vec![1u8; 10]);
+            });
+        });
+        let msg = res.unwrap_err().downcast::<String>().unwrap();
+        assert!(msg.starts_with("allocation budget exceeded"), "{}", msg);
+        assert!(msg.contains("test_assert_allocations_fails"), "{}", msg);
+    }
+}
@@ -0,0 +1,384 @@
+//! Exact live bytes accounting by named domain (e.g. "wal", "cache", "query").
+//!
+//! Code enters a domain with a [`DomainGuard`]: while the guard is alive, the allocations performed by the thread are
+//! charged to the domain, and freeing them credits the domain back, whichever thread and domain frees them.
+//! Allocations performed outside of any domain are not accounted for.
+
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicIsize, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use spin::{Mutex, RwLock};
+
+use crate::profiler::Profiler;
+
+type Callback = Arc<dyn Fn(&DomainQuotaEvent) + Send + Sync>;
+
+/// Passed to the quota callback of a domain.
+#[derive(Debug, Clone)]
+pub struct DomainQuotaEvent {
+    pub domain: String,
+    pub quota: usize,
+    pub live_bytes: isize,
+}
+
+/// The accounting of a domain, see [`domain_stats`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DomainStats {
+    pub name: String,
+    pub live_bytes: isize,
+    pub allocated_objects: isize,
+    pub allocated_bytes: isize,
+    pub freed_objects: isize,
+    pub freed_bytes: isize,
+    pub quota: Option<usize>,
+}
+
+struct DomainState {
+    name: String,
+    allocated_objects: AtomicIsize,
+    allocated_bytes: AtomicIsize,
+    freed_objects: AtomicIsize,
+    freed_bytes: AtomicIsize,
+    // 0 means no quota.
+    quota: AtomicUsize,
+    callback: RwLock<Option<Callback>>,
+    // set once the live bytes exceed the quota, until they get back enough below it.
+    exceeded: AtomicBool,
+}
+
+impl DomainState {
+    fn live_bytes(&self) -> isize {
+        self.allocated_bytes.load(Ordering::Relaxed) - self.freed_bytes.load(Ordering::Relaxed)
+    }
+
+    // Returns the quota event to fire if the live bytes just crossed the quota. Like the soft limit of the profiler,
+    // the quota is re-armed only once the live bytes drop below 90% of it.
+    fn check_quota(&self) -> Option<(Callback, DomainQuotaEvent)> {
+        let quota = self.quota.load(Ordering::Relaxed);
+        if quota == 0 {
+            return None;
+        }
+        let live_bytes = self.live_bytes();
+        let limit = quota as isize;
+        if live_bytes >= limit {
+            if !self.exceeded.swap(true, Ordering::Relaxed) {
+                let callback = self.callback.read().clone()?;
+                let event = DomainQuotaEvent {
+                    domain: self.name.clone(),
+                    quota,
+                    live_bytes,
+                };
+                return Some((callback, event));
+            }
+        } else if live_bytes < limit - limit / 10 {
+            self.exceeded.store(false, Ordering::Relaxed);
+        }
+        None
+    }
+}
+
+// Domain ids are indexes in the registry, plus one: 0 means "no domain".
+static DOMAINS: RwLock<Vec<Arc<DomainState>>> = RwLock::new(Vec::new());
+// The domain and usable size of the live allocations charged to a domain, by address. The map is sharded by address
+// so that the frees performed by concurrent threads don't contend on a single lock.
+type Owners = Mutex<Option<HashMap<usize, (usize, isize)>>>;
+const OWNERS_SHARDS: usize = 64;
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_OWNERS: Owners = Mutex::new(None);
+static OWNERS: [Owners; OWNERS_SHARDS] = [NO_OWNERS; OWNERS_SHARDS];
+// Number of entries in OWNERS, so that frees can skip the lookup when no domain is in use.
+static OWNED: AtomicUsize = AtomicUsize::new(0);
+
+// Returns the shard of OWNERS holding the block at `ptr`. Blocks are at least 16 bytes aligned, and large ones page
+// aligned, so the bits above the page offset are folded in.
+fn owners(ptr: usize) -> &'static Owners {
+    &OWNERS[((ptr >> 4) ^ (ptr >> 12)) % OWNERS_SHARDS]
+}
+
+thread_local! {
+    static CURRENT_DOMAIN: Cell<usize> = Cell::new(0);
+}
+
+/// A named accounting domain. Domains are registered once and live for the whole process.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Domain {
+    id: usize,
+}
+
+impl Domain {
+    /// Returns the domain called `name`, registering it if needed.
+    pub fn named(name: &str) -> Self {
+        Profiler::untracked(|| {
+            let mut domains = DOMAINS.write();
+            let id = match domains.iter().position(|domain| domain.name == name) {
+                Some(pos) => pos + 1,
+                None => {
+                    domains.push(Arc::new(DomainState {
+                        name: name.to_string(),
+                        allocated_objects: AtomicIsize::new(0),
+                        allocated_bytes: AtomicIsize::new(0),
+                        freed_objects: AtomicIsize::new(0),
+                        freed_bytes: AtomicIsize::new(0),
+                        quota: AtomicUsize::new(0),
+                        callback: RwLock::new(None),
+                        exceeded: AtomicBool::new(false),
+                    }));
+                    domains.len()
+                }
+            };
+            Self { id }
+        })
+    }
+
+    pub fn name(&self) -> String {
+        self.state().name.clone()
+    }
+
+    /// Charges the allocations performed by the current thread to this domain until the guard is dropped.
+    pub fn enter(&self) -> DomainGuard {
+        let prev = CURRENT_DOMAIN.with(|d| d.replace(self.id));
+        DomainGuard {
+            prev,
+            _not_send: std::marker::PhantomData,
+        }
+    }
+
+    /// Invokes `callback` when the live bytes of the domain cross `bytes`. The callback runs on the allocating thread,
+    /// outside of any lock. The allocations and frees it performs are accounted for like any other, e.g. the memory it
+    /// releases is credited back to the domains it was charged to.
+    pub fn set_quota<F>(&self, bytes: usize, callback: F)
+    where
+        F: Fn(&DomainQuotaEvent) + Send + Sync + 'static,
+    {
+        let state = self.state();
+        Profiler::untracked(|| *state.callback.write() = Some(Arc::new(callback)));
+        state.exceeded.store(false, Ordering::Relaxed);
+        state.quota.store(bytes, Ordering::Relaxed);
+    }
+
+    pub fn clear_quota(&self) {
+        self.state().quota.store(0, Ordering::Relaxed);
+    }
+
+    pub fn stats(&self) -> DomainStats {
+        let state = self.state();
+        DomainStats {
+            name: state.name.clone(),
+            live_bytes: state.live_bytes(),
+            allocated_objects: state.allocated_objects.load(Ordering::Relaxed),
+            allocated_bytes: state.allocated_bytes.load(Ordering::Relaxed),
+            freed_objects: state.freed_objects.load(Ordering::Relaxed),
+            freed_bytes: state.freed_bytes.load(Ordering::Relaxed),
+            quota: match state.quota.load(Ordering::Relaxed) {
+                0 => None,
+                quota => Some(quota),
+            },
+        }
+    }
+
+    fn state(&self) -> Arc<DomainState> {
+        Profiler::untracked(|| DOMAINS.read()[self.id - 1].clone())
+    }
+}
+
+impl fmt::Debug for Domain {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Domain").field(&self.name()).finish()
+    }
+}
+
+/// RAII structure restoring the previous domain of the thread when dropped, see [`Domain::enter`].
+pub struct DomainGuard {
+    prev: usize,
+    // the domain is tied to the thread that entered it.
+    _not_send: std::marker::PhantomData<*const ()>,
+}
+
+impl Drop for DomainGuard {
+    fn drop(&mut self) {
+        CURRENT_DOMAIN.with(|d| d.set(self.prev));
+    }
+}
+
+/// Charges the allocations performed by the current thread to the domain called `name` until the guard is dropped.
+pub fn enter_domain(name: &str) -> DomainGuard {
+    Domain::named(name).enter()
+}
+
+/// Returns the current accounting of every domain.
+pub fn domain_stats() -> Vec<DomainStats> {
+    let domains = Profiler::untracked(|| DOMAINS.read().clone());
+    (1..=domains.len())
+        .map(|id| Domain { id }.stats())
+        .collect()
+}
+
+// Called by the allocation hooks for every allocation of `size` usable bytes at `ptr`.
+#[cfg_attr(not(feature = "enable_heap_profiler"), allow(dead_code))]
+pub(crate) fn track_allocated(ptr: usize, size: isize) {
+    let id = CURRENT_DOMAIN.with(|d| d.get());
+    if id == 0 || size <= 0 {
+        return;
+    }
+    let quota = Profiler::non_reentrant(|| {
+        let quota = {
+            let domains = DOMAINS.read();
+            let domain = &domains[id - 1];
+            domain.allocated_objects.fetch_add(1, Ordering::Relaxed);
+            domain.allocated_bytes.fetch_add(size, Ordering::Relaxed);
+            domain.check_quota()
+        };
+        set_owner(ptr, id, size);
+        quota
+    });
+    fire_quota(quota.flatten());
+}
+
+// Called by the free hook: credits the domain that allocated `ptr`, if any.
+#[cfg_attr(not(feature = "enable_heap_profiler"), allow(dead_code))]
+pub(crate) fn track_freed(ptr: usize) {
+    if OWNED.load(Ordering::Relaxed) == 0 {
+        return;
+    }
+    Profiler::non_reentrant(|| {
+        let owner = owners(ptr)
+            .lock()
+            .as_mut()
+            .and_then(|owners| owners.remove(&ptr));
+        let Some((id, size)) = owner else {
+            return;
+        };
+        OWNED.fetch_sub(1, Ordering::Relaxed);
+        credit_freed(id, size);
+    });
+}
+
+// Called by the realloc hook: the block keeps being charged to the domain that allocated it. Blocks which weren't
+// charged to any domain are charged to the current domain of the thread, if any.
+#[cfg_attr(not(feature = "enable_heap_profiler"), allow(dead_code))]
+pub(crate) fn track_reallocated(old_ptr: usize, new_ptr: usize, new_size: isize) {
+    if OWNED.load(Ordering::Relaxed) == 0 {
+        track_allocated(new_ptr, new_size);
+        return;
+    }
+    let owned = Profiler::non_reentrant(|| {
+        let owner = owners(old_ptr)
+            .lock()
+            .as_mut()
+            .and_then(|owners| owners.remove(&old_ptr));
+        let Some((id, old_size)) = owner else {
+            return None;
+        };
+        OWNED.fetch_sub(1, Ordering::Relaxed);
+        let quota = {
+            let domains = DOMAINS.read();
+            let domain = &domains[id - 1];
+            domain
+                .allocated_bytes
+                .fetch_add(new_size - old_size, Ordering::Relaxed);
+            domain.check_quota()
+        };
+        set_owner(new_ptr, id, new_size);
+        Some(quota)
+    });
+    match owned {
+        Some(Some(quota)) => fire_quota(quota),
+        Some(None) => track_allocated(new_ptr, new_size),
+        // reentrant, not accounted for.
+        None => {}
+    }
+}
+
+// Records that the block at `ptr` is charged to the domain `id`. A block still recorded at that address has been
+// freed without being credited back, which happens if its free couldn't be tracked: it is credited back now.
+fn set_owner(ptr: usize, id: usize, size: isize) {
+    let stale = owners(ptr)
+        .lock()
+        .get_or_insert_with(HashMap::new)
+        .insert(ptr, (id, size));
+    match stale {
+        Some((stale_id, stale_size)) => credit_freed(stale_id, stale_size),
+        None => {
+            OWNED.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+fn credit_freed(id: usize, size: isize) {
+    let domains = DOMAINS.read();
+    let domain = &domains[id - 1];
+    domain.freed_objects.fetch_add(1, Ordering::Relaxed);
+    domain.freed_bytes.fetch_add(size, Ordering::Relaxed);
+    domain.check_quota();
+}
+
+// Invokes the quota callback, if any. It runs outside of `Profiler::non_reentrant`, so that the frees it performs
+// (e.g. to shed load) are credited back to their domains.
+fn fire_quota(quota: Option<(Callback, DomainQuotaEvent)>) {
+    if let Some((callback, event)) = quota {
+        callback(&event);
+    }
+}
+
+#[cfg(all(test, feature = "enable_heap_profiler"))]
+mod test {
+    use super::*;
+    use std::sync::atomic::AtomicIsize;
+
+    #[test]
+    fn test_domains() {
+        static QUOTA_EXCEEDED_AT: AtomicIsize = AtomicIsize::new(0);
+        let domain = Domain::named("test_domains");
+        domain.set_quota(3 << 20, |event| {
+            QUOTA_EXCEEDED_AT.store(event.live_bytes, Ordering::SeqCst)
+        });
+
+        let mut v = {
+            let _guard = domain.enter();
+            vec![0u8; 2 << 20]
+        };
+        std::hint::black_box(vec![0u8; 2 << 20]);
+        assert_eq!(domain.stats().live_bytes, 2 << 20);
+        assert_eq!(QUOTA_EXCEEDED_AT.load(Ordering::SeqCst), 0);
+
+        // growing a block keeps charging the domain it was allocated in.
+        v.reserve_exact(2 << 20);
+        assert_eq!(domain.stats().live_bytes, 4 << 20);
+        assert_eq!(QUOTA_EXCEEDED_AT.load(Ordering::SeqCst), 4 << 20);
+
+        // frees are credited back from any thread.
+        std::thread::spawn(move || drop(v)).join().unwrap();
+        let stats = domain_stats()
+            .into_iter()
+            .find(|stats| stats.name == "test_domains")
+            .unwrap();
+        assert_eq!(stats.live_bytes, 0);
+        assert_eq!((stats.allocated_objects, stats.freed_objects), (1, 1));
+        assert_eq!(stats.quota, Some(3 << 20));
+    }
+
+    #[test]
+    fn test_domain_quota_callback_frees() {
+        static BALLAST: Mutex<Vec<u8>> = Mutex::new(Vec::new());
+        let domain = Domain::named("test_domain_quota_callback_frees");
+        domain.set_quota(3 << 20, |_| {
+            // shed load.
+            drop(std::mem::take(&mut *BALLAST.lock()));
+        });
+
+        let v = {
+            let _guard = domain.enter();
+            *BALLAST.lock() = vec![0u8; 2 << 20];
+            vec![0u8; 2 << 20]
+        };
+        let stats = domain.stats();
+        assert_eq!(stats.live_bytes, 2 << 20);
+        assert_eq!((stats.allocated_objects, stats.freed_objects), (2, 1));
+
+        drop(v);
+        assert_eq!(domain.stats().live_bytes, 0);
+    }
+}
@@ -0,0 +1,370 @@
+//! Periodic heap profile dumps to a directory, for sidecar collectors to pick up.
+//!
+//! Every interval the dumper writes a snapshot of the running profiler as `<prefix>.<pid>.<seq>.<unix time>.pb`
+//! (and optionally the matching `.svg` flamegraph). Files are written to a temporary name and then renamed, so a
+//! collector never sees a partial file. Once written, the oldest dumps with the same prefix are removed to keep the
+//! directory within the configured number of files and bytes.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, SystemTime};
+
+use crate::{HeapProfilerGuard, HeapProfilerGuardBuilder, HeapReport, Result};
+
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(60);
+const DEFAULT_PREFIX: &str = "heappy";
+
+/// Configures and starts a [`Dumper`].
+#[derive(Debug, Clone)]
+pub struct DumperBuilder {
+    dir: PathBuf,
+    profiler: HeapProfilerGuardBuilder,
+    interval: Duration,
+    prefix: String,
+    svg: bool,
+    max_files: Option<usize>,
+    max_bytes: Option<u64>,
+    on_exit: bool,
+}
+
+impl DumperBuilder {
+    /// Dumps to `dir`, which is created if needed.
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Self {
+        Self {
+            dir: dir.into(),
+            profiler: HeapProfilerGuardBuilder::default(),
+            interval: DEFAULT_INTERVAL,
+            prefix: DEFAULT_PREFIX.to_string(),
+            svg: false,
+            max_files: None,
+            max_bytes: None,
+            on_exit: false,
+        }
+    }
+
+    /// Sets the options of the underlying heap profiler session.
+    pub fn profiler(mut self, profiler: HeapProfilerGuardBuilder) -> Self {
+        self.profiler = profiler;
+        self
+    }
+
+    /// Sets the interval between dumps. An interval of zero disables the periodic dumps.
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Sets the prefix of the file names, `heappy` by default.
+    pub fn prefix<S: Into<String>>(mut self, prefix: S) -> Self {
+        self.prefix = prefix.into();
+        self
+    }
+
+    /// Also writes a flamegraph of each dump.
+    pub fn svg(mut self, enabled: bool) -> Self {
+        self.svg = enabled;
+        self
+    }
+
+    /// Keeps at most `max_files` files in the directory. Dumps are removed whole, with their flamegraph.
+    pub fn max_files(mut self, max_files: usize) -> Self {
+        self.max_files = Some(max_files);
+        self
+    }
+
+    /// Keeps at most `max_bytes` bytes of files in the directory.
+    pub fn max_bytes(mut self, max_bytes: u64) -> Self {
+        self.max_bytes = Some(max_bytes);
+        self
+    }
+
+    /// Also dumps when the process exits, unless the dumper has been dropped by then.
+    pub fn on_exit(mut self, enabled: bool) -> Self {
+        self.on_exit = enabled;
+        self
+    }
+
+    pub fn build(self) -> Result<Dumper> {
+        std::fs::create_dir_all(&self.dir)?;
+        let guard = self.profiler.clone().build()?;
+        let shared = Arc::new(Shared {
+            options: self,
+            seq: AtomicU64::new(0),
+            stopped: Mutex::new(false),
+            stop: Condvar::new(),
+        });
+
+        if shared.options.on_exit {
+            static REGISTER: std::sync::Once = std::sync::Once::new();
+            *lock(&EXIT_DUMPER) = Some(shared.clone());
+            REGISTER.call_once(|| unsafe {
+                libc::atexit(dump_at_exit);
+            });
+        }
+
+        let thread = {
+            let shared = shared.clone();
+            std::thread::Builder::new()
+                .name("heappy-dumper".to_string())
+                .spawn(move || shared.run())
+                .expect("cannot spawn the dumper thread")
+        };
+
+        Ok(Dumper {
+            shared,
+            thread: Some(thread),
+            _guard: guard,
+        })
+    }
+}
+
+/// RAII structure keeping the heap profiler running until dropped, while a background thread dumps its snapshots.
+/// Only one heap profiler can run at a time.
+pub struct Dumper {
+    shared: Arc<Shared>,
+    thread: Option<JoinHandle<()>>,
+    // declared last: the dumper thread must be stopped before the profiler.
+    _guard: HeapProfilerGuard,
+}
+
+impl Dumper {
+    /// Dumps a snapshot right away, returning the path of the pprof file.
+    pub fn dump(&self) -> std::io::Result<PathBuf> {
+        self.shared.dump()
+    }
+}
+
+impl Drop for Dumper {
+    fn drop(&mut self) {
+        let mut exit_dumper = lock(&EXIT_DUMPER);
+        if exit_dumper
+            .as_ref()
+            .map_or(false, |exit_dumper| Arc::ptr_eq(exit_dumper, &self.shared))
+        {
+            *exit_dumper = None;
+        }
+        std::mem::drop(exit_dumper);
+
+        *lock(&self.shared.stopped) = true;
+        self.shared.stop.notify_all();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+// The dumper to run when the process exits, see `DumperBuilder::on_exit`.
+static EXIT_DUMPER: Mutex<Option<Arc<Shared>>> = Mutex::new(None);
+
+extern "C" fn dump_at_exit() {
+    let Some(shared) = lock(&EXIT_DUMPER).take() else {
+        return;
+    };
+    if let Err(err) = shared.dump() {
+        eprintln!(
+            "heappy: cannot dump heap profile to {}: {}",
+            shared.options.dir.display(),
+            err
+        );
+    }
+}
+
+struct Shared {
+    options: DumperBuilder,
+    seq: AtomicU64,
+    stopped: Mutex<bool>,
+    stop: Condvar,
+}
+
+impl Shared {
+    fn run(&self) {
+        loop {
+            let stopped = lock(&self.stopped);
+            let stopped = if self.options.interval.is_zero() {
+                self.stop
+                    .wait_while(stopped, |stopped| !*stopped)
+                    .map_or(true, |stopped| *stopped)
+            } else {
+                self.stop
+                    .wait_timeout_while(stopped, self.options.interval, |stopped| !*stopped)
+                    .map_or(true, |(stopped, _)| *stopped)
+            };
+            if stopped {
+                return;
+            }
+            if let Err(err) = self.dump() {
+                eprintln!(
+                    "heappy: cannot dump heap profile to {}: {}",
+                    self.options.dir.display(),
+                    err
+                );
+            }
+        }
+    }
+
+    fn dump(&self) -> std::io::Result<PathBuf> {
+        let options = &self.options;
+        let report = HeapReport::snapshot();
+        let timestamp = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let name = format!(
+            "{}.{}.{:06}.{}",
+            options.prefix,
+            std::process::id(),
+            self.seq.fetch_add(1, Ordering::Relaxed),
+            timestamp
+        );
+
+        let path = options.dir.join(format!("{}.pb", name));
+        write_atomically(&path, |file| report.write_pprof(file))?;
+        // the flamegraph renderer refuses to render empty profiles.
+        if options.svg && report.records().next().is_some() {
+            let svg = options.dir.join(format!("{}.svg", name));
+            write_atomically(&svg, |file| {
+                report
+                    .flamegraph_with_options(file, &Default::default())
+                    .map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err))
+            })?;
+        }
+
+        self.enforce_retention()?;
+        Ok(path)
+    }
+
+    // Removes the oldest dumps until the directory is within the configured limits. The `.pb` and `.svg` files of a
+    // dump are removed together.
+    fn enforce_retention(&self) -> std::io::Result<()> {
+        let options = &self.options;
+        if options.max_files.is_none() && options.max_bytes.is_none() {
+            return Ok(());
+        }
+
+        let prefix = format!("{}.", options.prefix);
+        let mut dumps: HashMap<String, Dump> = HashMap::new();
+        for entry in std::fs::read_dir(&options.dir)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if !name.starts_with(&prefix) || name.ends_with(".tmp") {
+                continue;
+            }
+            let metadata = entry.metadata()?;
+            if !metadata.is_file() {
+                continue;
+            }
+            let stem = name
+                .rsplit_once('.')
+                .map_or(name.as_str(), |(stem, _)| stem);
+            let modified = metadata.modified()?;
+            let dump = dumps.entry(stem.to_string()).or_insert_with(|| Dump {
+                modified,
+                bytes: 0,
+                files: vec![],
+            });
+            dump.modified = dump.modified.min(modified);
+            dump.bytes += metadata.len();
+            dump.files.push(name);
+        }
+        let mut dumps = dumps.into_iter().collect::<Vec<_>>();
+        dumps.sort_by(|(a_stem, a), (b_stem, b)| (a.modified, a_stem).cmp(&(b.modified, b_stem)));
+
+        let mut count: usize = dumps.iter().map(|(_, dump)| dump.files.len()).sum();
+        let mut bytes: u64 = dumps.iter().map(|(_, dump)| dump.bytes).sum();
+        for (_, dump) in dumps {
+            let too_many = options.max_files.map_or(false, |max| count > max);
+            let too_big = options.max_bytes.map_or(false, |max| bytes > max);
+            if !too_many && !too_big {
+                break;
+            }
+            for name in &dump.files {
+                std::fs::remove_file(options.dir.join(name))?;
+            }
+            count -= dump.files.len();
+            bytes -= dump.bytes;
+        }
+        Ok(())
+    }
+}
+
+// The files of a dump, which share their name up to the extension.
+struct Dump {
+    modified: SystemTime,
+    bytes: u64,
+    files: Vec<String>,
+}
+
+// Writes a file under a temporary name and renames it once complete.
+pub(crate) fn write_atomically<F>(path: &Path, write: F) -> std::io::Result<()>
+where
+    F: FnOnce(&mut std::fs::File) -> std::io::Result<()>,
+{
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+
+    let res = std::fs::File::create(&tmp).and_then(|mut file| {
+        write(&mut file)?;
+        file.sync_all()
+    });
+    match res {
+        Ok(()) => std::fs::rename(&tmp, path),
+        Err(err) => {
+            let _ = std::fs::remove_file(&tmp);
+            Err(err)
+        }
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+#[cfg(all(test, feature = "enable_heap_profiler"))]
+mod test {
+    use super::*;
+    use crate::profiler::test::{serial, test_profiler};
+
+    #[test]
+    fn test_dumper() {
+        let _serial = serial();
+        let dir = std::env::temp_dir().join(format!("heappy-dumper-{}", std::process::id()));
+        let dumper = DumperBuilder::new(&dir)
+            .profiler(test_profiler())
+            .interval(Duration::from_secs(3600))
+            .svg(true)
+            .max_files(3)
+            .build()
+            .unwrap();
+
+        let mut paths = vec![];
+        for _ in 0..3 {
+            std::hint::black_box(vec![0u8; 1 << 20]);
+            paths.push(dumper.dump().unwrap());
+        }
+        drop(dumper);
+
+        let prefix = format!("heappy.{}.", std::process::id());
+        let name = paths[2].file_name().unwrap().to_string_lossy().into_owned();
+        assert!(name.starts_with(&format!("{}000002.", prefix)));
+        assert!(name.ends_with(".pb"));
+
+        let mut names = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect::<Vec<_>>();
+        names.sort();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        // the oldest dumps have been removed with their flamegraph, and no temporary file is left behind.
+        assert_eq!(names.len(), 2);
+        assert!(names
+            .iter()
+            .all(|name| name.starts_with(&format!("{}000002.", prefix))));
+        assert!(names[0].ends_with(".pb"));
+        assert!(names[1].ends_with(".svg"));
+    }
+}
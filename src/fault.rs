@@ -0,0 +1,244 @@
+//! Allocation fault injection: make selected allocations fail, to exercise the out of memory handling paths
+//! (e.g. `try_reserve`) deterministically.
+
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Mutex, MutexGuard};
+
+use spin::RwLock;
+
+use crate::profiler::{current_thread_id, Profiler};
+use crate::{Error, Result};
+
+static FAULT_INJECTION_ENABLED: AtomicBool = AtomicBool::new(false);
+static FAULT_INJECTION_STATE: RwLock<Option<FaultInjectionState>> = RwLock::new(None);
+static FAULT_INJECTION_ENTER: Mutex<()> = Mutex::new(());
+
+// Candidate allocations seen and failures injected by the current fault injection session.
+static CANDIDATES: AtomicU64 = AtomicU64::new(0);
+static INJECTED: AtomicUsize = AtomicUsize::new(0);
+
+struct FaultInjectionState {
+    nth: Option<u64>,
+    probability: Option<f64>,
+    pattern: Option<String>,
+    thread_id: Option<u64>,
+    rng: AtomicU64,
+}
+
+/// RAII structure used to stop injecting allocation failures when dropped.
+pub struct FaultInjectionGuard {
+    _guard: MutexGuard<'static, ()>,
+}
+
+impl FaultInjectionGuard {
+    /// Returns how many allocations have been made to fail so far.
+    pub fn injected(&self) -> usize {
+        INJECTED.load(Ordering::SeqCst)
+    }
+}
+
+impl Drop for FaultInjectionGuard {
+    fn drop(&mut self) {
+        FAULT_INJECTION_ENABLED.store(false, Ordering::SeqCst);
+        // make sure no allocation is still looking at the state before tearing it down.
+        let state = FAULT_INJECTION_STATE.write().take();
+        std::mem::drop(state);
+    }
+}
+
+/// Configures and starts allocation fault injection.
+///
+/// The allocations from stacks matching the [`pattern`](Self::pattern) (all of them by default) are candidates for
+/// failure. A candidate fails if it's the [`nth`](Self::nth) one, or with the given
+/// [`probability`](Self::probability). When neither is configured every candidate fails.
+#[derive(Debug, Clone, Default)]
+pub struct FaultInjectionGuardBuilder {
+    nth: Option<u64>,
+    probability: Option<f64>,
+    pattern: Option<String>,
+    seed: Option<u64>,
+    current_thread_only: bool,
+}
+
+impl FaultInjectionGuardBuilder {
+    /// Fail the `n`th candidate allocation (counting from 1).
+    pub fn nth(mut self, n: u64) -> Self {
+        self.nth = Some(n);
+        self
+    }
+
+    /// Fail a random fraction of the candidate allocations, between 0.0 and 1.0.
+    pub fn probability(mut self, probability: f64) -> Self {
+        self.probability = Some(probability);
+        self
+    }
+
+    /// Seed of the random number generator used by [`probability`](Self::probability), for reproducible runs.
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
+    /// Only allocations whose backtrace contains a function whose demangled name contains `pattern` are candidates.
+    /// Note that this requires symbolizing the backtrace of every allocation, which is very slow.
+    pub fn pattern<S: Into<String>>(mut self, pattern: S) -> Self {
+        self.pattern = Some(pattern.into());
+        self
+    }
+
+    /// Only the allocations performed by the thread that builds the guard are candidates.
+    pub fn current_thread_only(mut self) -> Self {
+        self.current_thread_only = true;
+        self
+    }
+
+    pub fn build(self) -> Result<FaultInjectionGuard> {
+        let guard = FAULT_INJECTION_ENTER
+            .try_lock()
+            .map_err(|_| Error::ConcurrentFaultInjection)?;
+
+        let seed = self.seed.unwrap_or_else(|| {
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_nanos() as u64
+        });
+        let state = FaultInjectionState {
+            nth: self.nth,
+            probability: self.probability,
+            pattern: self.pattern,
+            thread_id: self.current_thread_only.then(current_thread_id),
+            // xorshift gets stuck on zero.
+            rng: AtomicU64::new(seed | 1),
+        };
+        CANDIDATES.store(0, Ordering::SeqCst);
+        INJECTED.store(0, Ordering::SeqCst);
+        *FAULT_INJECTION_STATE.write() = Some(state);
+        FAULT_INJECTION_ENABLED.store(true, Ordering::SeqCst);
+
+        Ok(FaultInjectionGuard { _guard: guard })
+    }
+}
+
+impl FaultInjectionState {
+    fn is_candidate(&self) -> bool {
+        if let Some(thread_id) = self.thread_id {
+            if thread_id != current_thread_id() {
+                return false;
+            }
+        }
+        match &self.pattern {
+            Some(pattern) => backtrace_matches(pattern),
+            None => true,
+        }
+    }
+
+    fn should_fail(&self) -> bool {
+        if !self.is_candidate() {
+            return false;
+        }
+        let n = CANDIDATES.fetch_add(1, Ordering::SeqCst) + 1;
+        let fail = match (self.nth, self.probability) {
+            (None, None) => true,
+            (nth, probability) => {
+                nth == Some(n) || probability.map_or(false, |p| self.next_random() < p)
+            }
+        };
+        if fail {
+            INJECTED.fetch_add(1, Ordering::SeqCst);
+        }
+        fail
+    }
+
+    // Returns a random number in [0, 1), using xorshift64.
+    fn next_random(&self) -> f64 {
+        let mut x = self.rng.load(Ordering::Relaxed);
+        loop {
+            let mut next = x;
+            next ^= next << 13;
+            next ^= next >> 7;
+            next ^= next << 17;
+            match self
+                .rng
+                .compare_exchange_weak(x, next, Ordering::Relaxed, Ordering::Relaxed)
+            {
+                Ok(_) => return (next >> 11) as f64 / (1u64 << 53) as f64,
+                Err(current) => x = current,
+            }
+        }
+    }
+}
+
+fn backtrace_matches(pattern: &str) -> bool {
+    let mut matches = false;
+    backtrace::trace(|frame| {
+        backtrace::resolve_frame(frame, |symbol| {
+            if let Some(name) = symbol.name() {
+                matches |= format!("{:#}", name).contains(pattern);
+            }
+        });
+        !matches
+    });
+    matches
+}
+
+// Called by the allocation hooks before allocating: returns true if the allocation should fail instead.
+#[cfg_attr(not(feature = "enable_heap_profiler"), allow(dead_code))]
+pub(crate) fn inject_failure() -> bool {
+    if !FAULT_INJECTION_ENABLED.load(Ordering::Relaxed) {
+        return false;
+    }
+    // allocations made while deciding (e.g. to symbolize the backtrace) never fail.
+    Profiler::non_reentrant(|| {
+        FAULT_INJECTION_STATE
+            .read()
+            .as_ref()
+            .map_or(false, FaultInjectionState::should_fail)
+    })
+    .unwrap_or(false)
+}
+
+#[cfg(all(test, feature = "enable_heap_profiler"))]
+mod test {
+    use super::*;
+    use crate::profiler::test::serial;
+
+    #[test]
+    fn test_fail_nth() {
+        let _serial = serial();
+        let mut a = Vec::<u8>::new();
+        let mut b = Vec::<u8>::new();
+        let mut c = Vec::<u8>::new();
+        let guard = FaultInjectionGuardBuilder::default()
+            .nth(2)
+            .current_thread_only()
+            .build()
+            .unwrap();
+        let res = (a.try_reserve(16), b.try_reserve(16), c.try_reserve(16));
+        assert_eq!(guard.injected(), 1);
+        drop(guard);
+
+        assert!(res.0.is_ok());
+        assert!(res.1.is_err());
+        assert!(res.2.is_ok());
+    }
+
+    #[test]
+    fn test_fail_pattern() {
+        let _serial = serial();
+        fn allocate_here(v: &mut Vec<u8>) -> bool {
+            v.try_reserve(16).is_ok()
+        }
+        let mut a = Vec::<u8>::new();
+        let mut b = Vec::<u8>::new();
+        let guard = FaultInjectionGuardBuilder::default()
+            .pattern("allocate_here")
+            .current_thread_only()
+            .build()
+            .unwrap();
+        let res = (a.try_reserve(16).is_ok(), allocate_here(&mut b));
+        drop(guard);
+
+        assert_eq!(res, (true, false));
+    }
+}
@@ -0,0 +1,99 @@
+//! Flamegraph rendering options, see [`HeapReport::flamegraph_with_options`](crate::HeapReport::flamegraph_with_options).
+
+use pprof::flamegraph::color::{BasicPalette, Palette};
+
+use crate::{Error, Metric, Result};
+
+/// How to render a flamegraph of a [`HeapReport`](crate::HeapReport).
+#[derive(Debug, Clone)]
+pub struct FlamegraphOptions {
+    pub(crate) metric: Metric,
+    title: Option<String>,
+    inverted: bool,
+    min_width: f64,
+    palette: Palette,
+    reverse_stack_order: bool,
+}
+
+impl Default for FlamegraphOptions {
+    fn default() -> Self {
+        Self {
+            metric: Metric::AllocBytes,
+            title: None,
+            inverted: false,
+            min_width: 0.1,
+            palette: Palette::Basic(BasicPalette::Mem),
+            reverse_stack_order: false,
+        }
+    }
+}
+
+impl FlamegraphOptions {
+    /// The value the width of the frames is proportional to, the allocated bytes by default.
+    pub fn metric(mut self, metric: Metric) -> Self {
+        self.metric = metric;
+        self
+    }
+
+    pub fn title<S: Into<String>>(mut self, title: S) -> Self {
+        self.title = Some(title.into());
+        self
+    }
+
+    /// Renders an icicle graph, with the stacks growing from the top down.
+    pub fn inverted(mut self, inverted: bool) -> Self {
+        self.inverted = inverted;
+        self
+    }
+
+    /// Leaves out the frames narrower than `pixels`.
+    pub fn min_width(mut self, pixels: f64) -> Self {
+        self.min_width = pixels;
+        self
+    }
+
+    pub fn palette(mut self, palette: Palette) -> Self {
+        self.palette = palette;
+        self
+    }
+
+    /// Puts the allocating functions at the root and their callers above them.
+    pub fn reverse_stack_order(mut self, reverse: bool) -> Self {
+        self.reverse_stack_order = reverse;
+        self
+    }
+
+    pub(crate) fn inferno_options(&self) -> pprof::flamegraph::Options<'static> {
+        let mut options = pprof::flamegraph::Options::default();
+        options.count_name = if self.metric.is_objects() {
+            "objects"
+        } else {
+            "bytes"
+        }
+        .to_string();
+        if let Some(title) = &self.title {
+            options.title = title.clone();
+        }
+        if self.inverted {
+            options.direction = pprof::flamegraph::Direction::Inverted;
+        }
+        options.min_width = self.min_width;
+        options.colors = self.palette;
+        options.reverse_stack_order = self.reverse_stack_order;
+        options
+    }
+}
+
+// Renders collapsed stack lines (`root;caller;callee count`) as an svg flamegraph.
+pub(crate) fn render<'a, I, W>(
+    lines: I,
+    options: &mut pprof::flamegraph::Options<'_>,
+    writer: W,
+) -> Result<()>
+where
+    I: IntoIterator<Item = &'a str>,
+    W: std::io::Write,
+{
+    pprof::flamegraph::from_lines(options, lines, writer)
+        .map_err(|err| Error::Flamegraph(err.to_string()))
+}
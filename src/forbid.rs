@@ -0,0 +1,153 @@
+//! No-allocation zones: regions of code in which the current thread is not supposed to allocate.
+
+use std::cell::{Cell, RefCell};
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// What to do when an allocation happens in a no-allocation zone.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ForbidMode {
+    /// Log the allocation and its backtrace to stderr and carry on. Suitable for production.
+    Log,
+    /// Panic with the backtrace of the first offending allocation when the zone is exited. Suitable for tests.
+    Panic,
+    /// Log the allocation and its backtrace to stderr and abort the process immediately.
+    Abort,
+}
+
+static FORBIDDEN_ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    static MODE: Cell<Option<ForbidMode>> = Cell::new(None);
+    // report of the first offending allocation in a zone in `Panic` mode, raised when the zone is exited.
+    static VIOLATION: RefCell<Option<String>> = RefCell::new(None);
+}
+
+/// RAII structure marking the current thread as a no-allocation zone until dropped. Zones can be nested.
+pub struct ForbidAllocGuard {
+    prev: Option<ForbidMode>,
+    // the zone is tied to the thread that entered it.
+    _not_send: std::marker::PhantomData<*const ()>,
+}
+
+impl ForbidAllocGuard {
+    pub fn new(mode: ForbidMode) -> Self {
+        let prev = MODE.with(|m| m.replace(Some(mode)));
+        Self {
+            prev,
+            _not_send: std::marker::PhantomData,
+        }
+    }
+}
+
+impl Drop for ForbidAllocGuard {
+    fn drop(&mut self) {
+        MODE.with(|m| m.set(self.prev));
+        if self.prev == Some(ForbidMode::Panic) {
+            // let the outermost panicking zone report the violation.
+            return;
+        }
+        if let Some(violation) = VIOLATION.with(|v| v.borrow_mut().take()) {
+            if !std::thread::panicking() {
+                panic!("{}", violation);
+            }
+        }
+    }
+}
+
+/// Runs `f` as a no-allocation zone, panicking if it allocates on the current thread.
+pub fn forbid_alloc<F, R>(f: F) -> R
+where
+    F: FnOnce() -> R,
+{
+    let _guard = ForbidAllocGuard::new(ForbidMode::Panic);
+    f()
+}
+
+/// Returns the number of allocations performed in no-allocation zones since the process started.
+pub fn forbidden_allocations() -> usize {
+    FORBIDDEN_ALLOCATIONS.load(Ordering::Relaxed)
+}
+
+// Called by the profiler for every allocation of `size` bytes (with recursion already prevented).
+pub(crate) fn check_allocation(size: isize) {
+    let Some(mode) = MODE.with(|m| m.get()) else {
+        return;
+    };
+    FORBIDDEN_ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+
+    let mut report = String::new();
+    let _ = write!(
+        report,
+        "heappy: allocation of {} bytes in a no-allocation zone\n{:?}",
+        size,
+        backtrace::Backtrace::new()
+    );
+    match mode {
+        ForbidMode::Log => write_stderr(&report),
+        ForbidMode::Panic => VIOLATION.with(|v| {
+            v.borrow_mut().get_or_insert(report);
+        }),
+        ForbidMode::Abort => {
+            write_stderr(&report);
+            std::process::abort();
+        }
+    }
+}
+
+// Writes straight to the stderr file descriptor: the allocation might come from code which is already holding
+// the std stderr handle.
+pub(crate) fn write_stderr(msg: &str) {
+    let mut buf = msg.as_bytes();
+    while !buf.is_empty() {
+        let n = unsafe { libc::write(2, buf.as_ptr() as *const libc::c_void, buf.len()) };
+        if n <= 0 {
+            break;
+        }
+        buf = &buf[n as usize..];
+    }
+}
+
+#[cfg(all(test, feature = "enable_heap_profiler"))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_forbid_alloc() {
+        let sum = forbid_alloc(|| (0..10u64).sum::<u64>());
+        assert_eq!(sum, 45);
+
+        let res = std::panic::catch_unwind(|| {
+            forbid_alloc(|| {
+                std::hint::black_box(vec![1u8; 10]);
+            })
+        });
+        let msg = res.unwrap_err().downcast::<String>().unwrap();
+        assert!(msg.starts_with("heappy: allocation of"), "{}", msg);
+        assert!(msg.contains("test_forbid_alloc"), "{}", msg);
+    }
+
+    #[test]
+    fn test_forbid_alloc_log() {
+        let before = forbidden_allocations();
+        {
+            let _guard = ForbidAllocGuard::new(ForbidMode::Log);
+            std::hint::black_box(vec![1u8; 10]);
+        }
+        assert!(forbidden_allocations() > before);
+    }
+
+    #[test]
+    fn test_forbid_alloc_aligned() {
+        // allocations aligned above 16 bytes go through posix_memalign.
+        #[repr(align(64))]
+        struct Aligned64([u8; 64]);
+
+        let before = forbidden_allocations();
+        {
+            let _guard = ForbidAllocGuard::new(ForbidMode::Log);
+            std::hint::black_box(Box::new(Aligned64([0; 64])));
+        }
+        assert!(forbidden_allocations() > before);
+    }
+}
@@ -4,41 +4,101 @@
 //! [`aligned_alloc`].
 
 use crate::adapter::*;
+use crate::domain;
+use crate::fault;
+use crate::oom;
 use crate::profiler::Profiler;
+use crate::stats;
 use libc::{c_int, c_void, size_t};
 
 // On linux we need to reference at least one symbol in a module for it to not be pruned at link time.
 pub(crate) fn dummy_force_link() {}
 
+// Reads `HEAPPY_CONF` when the process starts, before `main` runs.
+#[used]
+#[cfg_attr(
+    any(target_os = "linux", target_os = "android"),
+    link_section = ".init_array"
+)]
+#[cfg_attr(target_os = "macos", link_section = "__DATA,__mod_init_func")]
+static INIT: extern "C" fn() = crate::conf::init;
+
 #[no_mangle]
 pub unsafe extern "C" fn malloc(size: size_t) -> *mut c_void {
+    if fault::inject_failure() {
+        return std::ptr::null_mut();
+    }
     let res = sys_malloc(size);
-    Profiler::track_allocated(sys_malloc_usable_size(res) as isize);
+    if res.is_null() {
+        oom::alloc_failed(size);
+    }
+    let usable = sys_malloc_usable_size(res) as isize;
+    if !res.is_null() {
+        stats::track_allocated(usable as usize);
+    }
+    Profiler::track_allocated(res as usize, usable, size as isize);
+    domain::track_allocated(res as usize, usable);
     res
 }
 
 #[no_mangle]
 pub unsafe extern "C" fn calloc(number: size_t, size: size_t) -> *mut c_void {
+    if fault::inject_failure() {
+        return std::ptr::null_mut();
+    }
     let res = sys_calloc(number, size);
-    Profiler::track_allocated(sys_malloc_usable_size(res) as isize);
+    if res.is_null() {
+        oom::alloc_failed(number.saturating_mul(size));
+    }
+    let usable = sys_malloc_usable_size(res) as isize;
+    if !res.is_null() {
+        stats::track_allocated(usable as usize);
+    }
+    Profiler::track_allocated(res as usize, usable, number.saturating_mul(size) as isize);
+    domain::track_allocated(res as usize, usable);
     res
 }
 
 #[no_mangle]
 pub unsafe extern "C" fn free(ptr: *mut c_void) {
-    #[cfg(feature = "measure_free")]
-    {
-        let size = sys_malloc_usable_size(ptr) as isize;
-        Profiler::track_allocated(-size);
+    if !ptr.is_null() {
+        let size = sys_malloc_usable_size(ptr);
+        stats::track_freed(size);
+        #[cfg(feature = "measure_free")]
+        Profiler::track_allocated(ptr as usize, -(size as isize), 0);
     }
+    domain::track_freed(ptr as usize);
     sys_free(ptr)
 }
 
 #[no_mangle]
 pub unsafe extern "C" fn realloc(ptr: *mut c_void, size: size_t) -> *mut c_void {
+    if fault::inject_failure() {
+        return std::ptr::null_mut();
+    }
     let old_size = sys_malloc_usable_size(ptr) as isize;
     let res = sys_realloc(ptr, size);
-    Profiler::track_allocated(sys_malloc_usable_size(res) as isize - old_size);
+    if res.is_null() {
+        if size != 0 {
+            // on failure the original block is left untouched.
+            oom::alloc_failed(size);
+        } else if !ptr.is_null() {
+            // jemalloc frees the block on a zero sized realloc.
+            stats::track_freed(old_size as usize);
+            #[cfg(feature = "measure_free")]
+            Profiler::track_allocated(ptr as usize, -old_size, 0);
+            domain::track_freed(ptr as usize);
+        }
+    } else {
+        let usable = sys_malloc_usable_size(res) as isize;
+        // a realloc counts as freeing the old block and allocating the new one.
+        if !ptr.is_null() {
+            stats::track_freed(old_size as usize);
+        }
+        stats::track_allocated(usable as usize);
+        Profiler::track_reallocated(ptr as usize, res as usize, old_size, usable, size as isize);
+        domain::track_reallocated(ptr as usize, res as usize, usable);
+    }
     res
 }
 
@@ -53,12 +113,35 @@ pub unsafe extern "C" fn posix_memalign(
     alignment: size_t,
     size: size_t,
 ) -> c_int {
-    sys_posix_memalign(ptr, alignment, size)
+    if fault::inject_failure() {
+        return libc::ENOMEM;
+    }
+    let res = sys_posix_memalign(ptr, alignment, size);
+    if res == libc::ENOMEM {
+        oom::alloc_failed(size);
+    } else if res == 0 {
+        let usable = sys_malloc_usable_size(*ptr) as isize;
+        stats::track_allocated(usable as usize);
+        Profiler::track_allocated(*ptr as usize, usable, size as isize);
+        domain::track_allocated(*ptr as usize, usable);
+    }
+    res
 }
 
 #[no_mangle]
 pub unsafe extern "C" fn aligned_alloc(alignment: size_t, size: size_t) -> *mut c_void {
+    if fault::inject_failure() {
+        return std::ptr::null_mut();
+    }
     let res = sys_aligned_alloc(alignment, size);
-    Profiler::track_allocated(sys_malloc_usable_size(res) as isize);
+    if res.is_null() {
+        oom::alloc_failed(size);
+    }
+    let usable = sys_malloc_usable_size(res) as isize;
+    if !res.is_null() {
+        stats::track_allocated(usable as usize);
+    }
+    Profiler::track_allocated(res as usize, usable, size as isize);
+    domain::track_allocated(res as usize, usable);
     res
 }
@@ -0,0 +1,372 @@
+//! HTTP endpoints compatible with Go's `net/http/pprof`, so that `go tool pprof http://host/debug/pprof/heap` works
+//! against a Rust service:
+//!
+//! * `/debug/pprof/heap`: the heap profile, showing the in-use space by default (with `measure_free`).
+//! * `/debug/pprof/allocs`: the same profile, showing the allocated space by default.
+//! * `/debug/heappy/flamegraph.svg`: a flamegraph of the allocated bytes.
+//!
+//! Without parameters the endpoints return a snapshot of the running profiler. With `seconds=N` they return the
+//! allocations made during the next N seconds only, starting a profiler for the occasion if none is running. As
+//! with Go, N must be shorter than the 30 seconds write timeout of the server. Like Go's `gc=1`, which forces a
+//! collection before taking the profile, `gc=` is accepted; it has no effect since the in-use figures are always up
+//! to date.
+//!
+//! [`HttpServer`] is a small embedded server; services already running an HTTP server can instead route these paths
+//! to [`Handler::handle`].
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use crate::profiler::Profiler;
+use crate::{HeapProfilerGuardBuilder, HeapReport};
+
+/// A response to one of the profiling endpoints.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Response {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    fn error(status: u16, message: &str) -> Self {
+        Self {
+            status,
+            content_type: "text/plain; charset=utf-8",
+            body: format!("{}\n", message).into_bytes(),
+        }
+    }
+}
+
+/// Serves the profiling endpoints, independently of the HTTP server.
+#[derive(Debug, Clone, Default)]
+pub struct Handler {
+    profiler: HeapProfilerGuardBuilder,
+}
+
+impl Handler {
+    /// `profiler` configures the profiler started by requests passing `seconds=` while no profiler is running.
+    pub fn new(profiler: HeapProfilerGuardBuilder) -> Self {
+        Self { profiler }
+    }
+
+    /// Handles a GET request for `path_and_query`, e.g. `/debug/pprof/heap?seconds=30`.
+    pub fn handle(&self, path_and_query: &str) -> Response {
+        let (path, query) = path_and_query
+            .split_once('?')
+            .unwrap_or((path_and_query, ""));
+
+        // `gc=` and unknown parameters are ignored.
+        let mut seconds = 0;
+        for (key, value) in query.split('&').filter_map(|param| param.split_once('=')) {
+            if key == "seconds" {
+                let Ok(value) = value.parse() else {
+                    return Response::error(400, "invalid seconds parameter");
+                };
+                seconds = value;
+            }
+        }
+        // like Go, refuse to profile for longer than the server would wait for the response.
+        if Duration::from_secs(seconds) >= IO_TIMEOUT {
+            return Response::error(400, "profile duration exceeds the server's write timeout");
+        }
+
+        let default_sample_type = match path {
+            "/debug/pprof/heap" if cfg!(feature = "measure_free") => "inuse_space",
+            "/debug/pprof/heap" | "/debug/pprof/allocs" => "alloc_space",
+            "/debug/heappy/flamegraph.svg" => {
+                return match self.report(seconds) {
+                    Ok(report) if report.records().next().is_none() => {
+                        Response::error(404, "no allocation has been sampled")
+                    }
+                    Ok(report) => {
+                        let mut body = vec![];
+                        match report.flamegraph_with_options(&mut body, &Default::default()) {
+                            Ok(()) => Response {
+                                status: 200,
+                                content_type: "image/svg+xml",
+                                body,
+                            },
+                            Err(err) => Response::error(500, &err.to_string()),
+                        }
+                    }
+                    Err(err) => err,
+                }
+            }
+            _ => return Response::error(404, "not found"),
+        };
+
+        match self.report(seconds) {
+            Ok(report) => {
+                let mut proto = report.pprof();
+                if let Some(idx) = proto
+                    .string_table
+                    .iter()
+                    .position(|s| s == default_sample_type)
+                {
+                    proto.default_sample_type = idx as i64;
+                }
+                let mut body = vec![];
+                match pprof::protos::Message::encode(&proto, &mut body) {
+                    Ok(()) => Response {
+                        status: 200,
+                        content_type: "application/octet-stream",
+                        body,
+                    },
+                    Err(err) => Response::error(500, &err.to_string()),
+                }
+            }
+            Err(err) => err,
+        }
+    }
+
+    fn report(&self, seconds: u64) -> Result<HeapReport, Response> {
+        let duration = Duration::from_secs(seconds);
+        match (seconds, Profiler::enabled()) {
+            (0, true) => Ok(HeapReport::snapshot()),
+            (0, false) => Err(Response::error(
+                503,
+                "the heap profiler is not running, pass seconds= to profile for a while",
+            )),
+            (_, true) => {
+                let base = HeapReport::snapshot();
+                std::thread::sleep(duration);
+                Ok(HeapReport::snapshot().diff(&base))
+            }
+            (_, false) => {
+                let guard = self
+                    .profiler
+                    .clone()
+                    .build()
+                    .map_err(|err| Response::error(503, &err.to_string()))?;
+                std::thread::sleep(duration);
+                Ok(guard.report())
+            }
+        }
+    }
+}
+
+// How long a connection may stall while sending its request or reading the response, so that idle clients don't
+// hold their thread forever.
+const IO_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Maximum size of the request line and headers, so that clients can't make a connection buffer an unbounded request.
+const MAX_REQUEST_HEAD: u64 = 8192;
+
+// Maximum number of connections served at once; further connections are closed right away.
+const MAX_CONNECTIONS: usize = 16;
+
+/// A minimal HTTP/1.1 server serving the profiling endpoints, one thread per connection, up to 16 connections at once.
+/// It stops when dropped.
+pub struct HttpServer {
+    addr: SocketAddr,
+    stopped: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl HttpServer {
+    pub fn bind<A: ToSocketAddrs>(addr: A, handler: Handler) -> std::io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        let addr = listener.local_addr()?;
+        let stopped = Arc::new(AtomicBool::new(false));
+
+        let thread = {
+            let stopped = stopped.clone();
+            let handler = Arc::new(handler);
+            let connections = Arc::new(AtomicUsize::new(0));
+            std::thread::Builder::new()
+                .name("heappy-http".to_string())
+                .spawn(move || {
+                    for stream in listener.incoming() {
+                        if stopped.load(Ordering::SeqCst) {
+                            return;
+                        }
+                        let Ok(stream) = stream else {
+                            continue;
+                        };
+                        let Some(slot) = ConnectionSlot::acquire(&connections) else {
+                            continue;
+                        };
+                        let handler = handler.clone();
+                        // if the thread can't be spawned, the connection is closed and the slot released.
+                        let _ = std::thread::Builder::new()
+                            .name("heappy-http-conn".to_string())
+                            .spawn(move || {
+                                let _slot = slot;
+                                let _ = serve_connection(stream, &handler);
+                            });
+                    }
+                })?
+        };
+
+        Ok(Self {
+            addr,
+            stopped,
+            thread: Some(thread),
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+impl Drop for HttpServer {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        // wake up the accepting thread.
+        let _ = TcpStream::connect(self.addr);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+// One of the `MAX_CONNECTIONS` connections served at once, released when dropped.
+struct ConnectionSlot(Arc<AtomicUsize>);
+
+impl ConnectionSlot {
+    fn acquire(connections: &Arc<AtomicUsize>) -> Option<Self> {
+        if connections.fetch_add(1, Ordering::SeqCst) >= MAX_CONNECTIONS {
+            connections.fetch_sub(1, Ordering::SeqCst);
+            return None;
+        }
+        Some(Self(connections.clone()))
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+fn serve_connection(stream: TcpStream, handler: &Handler) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(IO_TIMEOUT))?;
+    stream.set_write_timeout(Some(IO_TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?).take(MAX_REQUEST_HEAD);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // skip the headers, the endpoints don't need any.
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 0 && header != "\r\n" && header != "\n" {
+        header.clear();
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let response = match (parts.next(), parts.next()) {
+        _ if reader.limit() == 0 => Response::error(431, "request header too large"),
+        (Some("GET"), Some(target)) => handler.handle(target),
+        (Some(_), Some(_)) => Response::error(405, "method not allowed"),
+        _ => Response::error(400, "bad request"),
+    };
+
+    let mut stream = stream;
+    write!(
+        stream,
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status,
+        reason(response.status),
+        response.content_type,
+        response.body.len()
+    )?;
+    stream.write_all(&response.body)?;
+    stream.flush()
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        503 => "Service Unavailable",
+        _ => "",
+    }
+}
+
+#[cfg(all(test, feature = "enable_heap_profiler"))]
+mod test {
+    use super::*;
+    use crate::profiler::test::{serial, test_profiler};
+
+    fn get(addr: SocketAddr, target: &str) -> (String, Vec<u8>) {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", target).unwrap();
+        let mut response = vec![];
+        stream.read_to_end(&mut response).unwrap();
+        let split = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+        let head = String::from_utf8(response[..split].to_vec()).unwrap();
+        (head, response[split + 4..].to_vec())
+    }
+
+    #[test]
+    fn test_http_server_request_too_large() {
+        let server = HttpServer::bind("127.0.0.1:0", Handler::default()).unwrap();
+        let mut stream = TcpStream::connect(server.local_addr()).unwrap();
+        // a request line that never ends.
+        stream
+            .write_all(&[b'a'; MAX_REQUEST_HEAD as usize])
+            .unwrap();
+        let mut response = vec![];
+        stream.read_to_end(&mut response).unwrap();
+        assert!(String::from_utf8(response)
+            .unwrap()
+            .starts_with("HTTP/1.1 431 "));
+    }
+
+    #[test]
+    fn test_seconds_too_long() {
+        let handler = Handler::default();
+        assert_eq!(handler.handle("/debug/pprof/heap?seconds=30").status, 400);
+        assert_eq!(
+            handler
+                .handle("/debug/pprof/heap?seconds=18446744073709551615")
+                .status,
+            400
+        );
+    }
+
+    #[test]
+    fn test_http_server() {
+        let _serial = serial();
+        let profiler = test_profiler();
+        let server = HttpServer::bind("127.0.0.1:0", Handler::new(profiler.clone())).unwrap();
+
+        let (head, _) = get(server.local_addr(), "/debug/pprof/heap");
+        assert!(head.starts_with("HTTP/1.1 503 "));
+        let (head, _) = get(server.local_addr(), "/debug/pprof/nope");
+        assert!(head.starts_with("HTTP/1.1 404 "));
+
+        let guard = profiler.build().unwrap();
+        std::hint::black_box(vec![0u8; 1 << 20]);
+
+        let (head, body) = get(server.local_addr(), "/debug/pprof/allocs?gc=1");
+        assert!(head.starts_with("HTTP/1.1 200 "));
+        let proto: pprof::protos::Profile =
+            pprof::protos::Message::decode(body.as_slice()).unwrap();
+        assert!(!proto.sample.is_empty());
+        assert_eq!(
+            proto.string_table[proto.default_sample_type as usize],
+            "alloc_space"
+        );
+
+        let (head, body) = get(server.local_addr(), "/debug/heappy/flamegraph.svg");
+        assert!(head.contains("Content-Type: image/svg+xml"));
+        assert!(String::from_utf8(body).unwrap().contains("<svg"));
+
+        // only the allocations made during the requested window are reported.
+        let (head, body) = get(server.local_addr(), "/debug/pprof/heap?seconds=1");
+        assert!(head.starts_with("HTTP/1.1 200 "));
+        let proto: pprof::protos::Profile =
+            pprof::protos::Message::decode(body.as_slice()).unwrap();
+        assert!(proto.sample.iter().all(|sample| sample.value[0] == 0));
+        drop(guard);
+    }
+}
@@ -2,6 +2,52 @@ mod profiler;
 pub use profiler::*;
 
 mod collector;
+pub use collector::{LifetimeHistogram, MemProfileRecord, Metric, SampleThreads, SizeHistogram};
+
+mod sample;
+pub use sample::{HeapSample, HeapTotals, StackFrame};
+
+mod flamegraph;
+pub use flamegraph::FlamegraphOptions;
+
+mod count;
+pub use count::{count_allocations, AllocationCount};
+
+mod forbid;
+pub use forbid::{forbid_alloc, forbidden_allocations, ForbidAllocGuard, ForbidMode};
+
+mod fault;
+pub use fault::{FaultInjectionGuard, FaultInjectionGuardBuilder};
+
+mod oom;
+
+mod stats;
+pub use stats::{stats, thread_stats, AllocationStats};
+
+mod domain;
+pub use domain::{domain_stats, enter_domain, Domain, DomainGuard, DomainQuotaEvent, DomainStats};
+
+mod continuous;
+pub use continuous::{ContinuousProfiler, ContinuousProfilerBuilder, HeapWindow};
+
+pub mod dumper;
+
+#[cfg(feature = "http_server")]
+pub mod http;
+
+mod conf;
+
+mod signal;
+pub use signal::dump_on_sigusr2;
+
+mod metrics;
+pub use metrics::write_openmetrics;
+
+#[cfg(feature = "measure_free")]
+mod limit;
+#[cfg(feature = "measure_free")]
+pub use limit::{SoftLimit, SoftLimitEvent};
+
 #[cfg(feature = "enable_heap_profiler")]
 mod hook;
 
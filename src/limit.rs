@@ -0,0 +1,169 @@
+//! Soft limit on the live bytes tracked by the heap profiler.
+
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use crate::forbid::write_stderr;
+use crate::profiler::Profiler;
+use crate::HeapReport;
+
+/// Passed to the soft limit callback.
+#[derive(Debug, Clone)]
+pub struct SoftLimitEvent {
+    /// The configured limit.
+    pub limit: usize,
+    /// Bytes allocated since the profiler has been started, minus the bytes freed since then.
+    ///
+    /// Frees of blocks allocated before the profiler was started are subtracted too, so in a process that was
+    /// already running this undercounts the memory in use and can even be negative.
+    pub live_bytes: isize,
+}
+
+type Callback = Arc<dyn Fn(&SoftLimitEvent) + Send + Sync>;
+
+/// A soft limit on the live bytes tracked by the profiler, see [`HeapProfilerGuardBuilder::soft_limit`].
+///
+/// When an allocation makes the live bytes cross the limit, the callback is invoked (and the profile dumped) from
+/// the allocating thread, outside of the profiler lock. The allocations and frees performed by the callback are
+/// tracked like any other, so that the memory it releases (e.g. by shedding load) counts towards the live bytes.
+/// The limit fires again only once the live bytes have dropped below 90% of it.
+///
+/// The live bytes only account for the activity since the profiler has been started (see
+/// [`SoftLimitEvent::live_bytes`]), not for the whole heap of the process.
+///
+/// [`HeapProfilerGuardBuilder::soft_limit`]: crate::HeapProfilerGuardBuilder::soft_limit
+#[derive(Clone)]
+pub struct SoftLimit {
+    bytes: usize,
+    callback: Option<Callback>,
+    dump_path: Option<PathBuf>,
+}
+
+impl SoftLimit {
+    pub fn new(bytes: usize) -> Self {
+        Self {
+            bytes,
+            callback: None,
+            dump_path: None,
+        }
+    }
+
+    /// Invoke `callback` when the limit is crossed.
+    pub fn callback<F>(mut self, callback: F) -> Self
+    where
+        F: Fn(&SoftLimitEvent) + Send + Sync + 'static,
+    {
+        self.callback = Some(Arc::new(callback));
+        self
+    }
+
+    /// Write a pprof profile of the allocations sampled so far to `path` when the limit is crossed.
+    pub fn dump_to<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.dump_path = Some(path.into());
+        self
+    }
+
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    pub(crate) fn fire(&self, live_bytes: isize) {
+        if let Some(path) = &self.dump_path {
+            Profiler::untracked(|| {
+                let report = HeapReport::snapshot();
+                let res =
+                    std::fs::File::create(path).and_then(|mut file| report.write_pprof(&mut file));
+                if let Err(err) = res {
+                    write_stderr(&format!(
+                        "heappy: cannot write soft limit profile to {}: {}\n",
+                        path.display(),
+                        err
+                    ));
+                }
+            });
+        }
+        if let Some(callback) = &self.callback {
+            callback(&SoftLimitEvent {
+                limit: self.bytes,
+                live_bytes,
+            });
+        }
+    }
+}
+
+impl fmt::Debug for SoftLimit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SoftLimit")
+            .field("bytes", &self.bytes)
+            .field("callback", &self.callback.is_some())
+            .field("dump_path", &self.dump_path)
+            .finish()
+    }
+}
+
+#[cfg(all(test, feature = "enable_heap_profiler"))]
+mod test {
+    use super::*;
+    use crate::profiler::test::{serial, test_profiler};
+    use std::sync::atomic::{AtomicIsize, AtomicUsize, Ordering};
+    use std::sync::Mutex;
+
+    #[test]
+    fn test_soft_limit() {
+        let _serial = serial();
+        static CROSSED_AT: AtomicIsize = AtomicIsize::new(0);
+        let path =
+            std::env::temp_dir().join(format!("heappy-soft-limit-{}.pb", std::process::id()));
+
+        let guard = test_profiler()
+            .soft_limit(
+                SoftLimit::new(3 << 20)
+                    .callback(|event| CROSSED_AT.store(event.live_bytes, Ordering::SeqCst))
+                    .dump_to(&path),
+            )
+            .build()
+            .unwrap();
+        let a = vec![0u8; 2 << 20];
+        assert_eq!(CROSSED_AT.load(Ordering::SeqCst), 0);
+        let b = vec![0u8; 2 << 20];
+        assert_eq!(CROSSED_AT.load(Ordering::SeqCst), 4 << 20);
+        drop((a, b));
+        drop(guard);
+
+        assert!(std::fs::metadata(&path).unwrap().len() > 0);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_soft_limit_callback_frees() {
+        let _serial = serial();
+        static BALLAST: Mutex<Vec<u8>> = Mutex::new(Vec::new());
+        static FIRED: AtomicUsize = AtomicUsize::new(0);
+        static CROSSED_AT: AtomicIsize = AtomicIsize::new(0);
+
+        let guard = test_profiler()
+            .current_thread_only()
+            .report_leaks(true)
+            .soft_limit(SoftLimit::new(3 << 20).callback(|event| {
+                FIRED.fetch_add(1, Ordering::SeqCst);
+                CROSSED_AT.store(event.live_bytes, Ordering::SeqCst);
+                // shed load.
+                drop(std::mem::take(&mut *BALLAST.lock().unwrap()));
+            }))
+            .build()
+            .unwrap();
+        *BALLAST.lock().unwrap() = vec![0u8; 2 << 20];
+        let a = vec![0u8; 2 << 20];
+        assert_eq!(FIRED.load(Ordering::SeqCst), 1);
+        assert_eq!(CROSSED_AT.load(Ordering::SeqCst), 4 << 20);
+        // the ballast freed by the callback brought the live bytes back below the limit, which re-armed it.
+        let b = vec![0u8; 2 << 20];
+        assert_eq!(FIRED.load(Ordering::SeqCst), 2);
+        assert_eq!(CROSSED_AT.load(Ordering::SeqCst), 4 << 20);
+        drop((a, b));
+
+        let report = guard.report();
+        assert_eq!(report.leaks().map(<[_]>::len), Some(0));
+    }
+}
@@ -0,0 +1,279 @@
+//! OpenMetrics (Prometheus) text exposition of heappy's counters and of the top allocation sites of a report.
+
+use std::collections::HashMap;
+use std::io::Write;
+
+use crate::collector::MemProfileRecord;
+use crate::profiler::stack_symbols;
+use crate::{domain_stats, stats, HeapReport};
+
+// Crates whose functions (and trait implementations) never make a meaningful site label.
+const SKIPPED_CRATES: &[&str] = &["alloc::", "core::", "std::", "hashbrown::", "__rust"];
+
+// The allocator hooks, which show up right below the profiler frames.
+const HOOKS: &[&str] = &[
+    "malloc",
+    "calloc",
+    "realloc",
+    "posix_memalign",
+    "aligned_alloc",
+];
+
+// Label value of the site aggregating every site beyond the top N.
+const OTHER_SITE: &str = "other";
+
+/// Writes heappy's process-wide and per-domain counters, followed by the allocations of the top `max_sites` call
+/// sites of `report` (if any), in the OpenMetrics text format.
+///
+/// A site is labelled with the name of the innermost function of its stacks outside of the standard library. The
+/// sites are ranked by allocated bytes and the ones beyond `max_sites` are summed up into the `other` site, which
+/// bounds the cardinality of the `site` label to `max_sites + 1`. Site values are sampled, not exact.
+pub fn write_openmetrics<W: Write>(
+    writer: &mut W,
+    report: Option<&HeapReport>,
+    max_sites: usize,
+) -> std::io::Result<()> {
+    let process = stats();
+    write_family(
+        writer,
+        "heappy_allocations",
+        "counter",
+        "Allocations performed by the process.",
+        &[(None, process.allocations as i64)],
+    )?;
+    write_family(
+        writer,
+        "heappy_allocated_bytes",
+        "counter",
+        "Bytes allocated by the process.",
+        &[(None, process.allocated_bytes as i64)],
+    )?;
+    write_family(
+        writer,
+        "heappy_frees",
+        "counter",
+        "Frees performed by the process.",
+        &[(None, process.frees as i64)],
+    )?;
+    write_family(
+        writer,
+        "heappy_freed_bytes",
+        "counter",
+        "Bytes freed by the process.",
+        &[(None, process.freed_bytes as i64)],
+    )?;
+    write_family(
+        writer,
+        "heappy_live_objects",
+        "gauge",
+        "Objects allocated and not yet freed.",
+        &[(None, process.live_objects())],
+    )?;
+    write_family(
+        writer,
+        "heappy_live_bytes",
+        "gauge",
+        "Bytes allocated and not yet freed.",
+        &[(None, process.live_bytes())],
+    )?;
+
+    let domains = domain_stats();
+    if !domains.is_empty() {
+        let samples = |value: fn(&crate::DomainStats) -> i64| {
+            domains
+                .iter()
+                .map(|domain| (Some(("domain", domain.name.as_str())), value(domain)))
+                .collect::<Vec<_>>()
+        };
+        write_family(
+            writer,
+            "heappy_domain_allocated_bytes",
+            "counter",
+            "Bytes allocated in the domain.",
+            &samples(|domain| domain.allocated_bytes as i64),
+        )?;
+        write_family(
+            writer,
+            "heappy_domain_live_bytes",
+            "gauge",
+            "Bytes allocated in the domain and not yet freed.",
+            &samples(|domain| domain.live_bytes as i64),
+        )?;
+    }
+
+    if let Some(report) = report {
+        write_sites(writer, report, max_sites)?;
+    }
+    writeln!(writer, "# EOF")
+}
+
+fn write_sites<W: Write>(
+    writer: &mut W,
+    report: &HeapReport,
+    max_sites: usize,
+) -> std::io::Result<()> {
+    let mut sites: HashMap<&str, MemProfileRecord> = HashMap::new();
+    let names = report
+        .records()
+        .map(|(frames, rec)| (site_name(frames), rec))
+        .collect::<Vec<_>>();
+    for (name, rec) in names.iter() {
+        sites.entry(name.as_str()).or_default().merge(rec);
+    }
+    let mut sites = sites.into_iter().collect::<Vec<_>>();
+    sites.sort_by_key(|(name, rec)| (std::cmp::Reverse(rec.alloc_bytes), *name));
+    if sites.len() > max_sites {
+        let mut other = MemProfileRecord::default();
+        for (_, rec) in sites.drain(max_sites..) {
+            other.merge(&rec);
+        }
+        sites.push((OTHER_SITE, other));
+    }
+
+    write_family(
+        writer,
+        "heappy_profile_period_bytes",
+        "gauge",
+        "Sampling period of the heap profile the site metrics come from.",
+        &[(None, report.period() as i64)],
+    )?;
+    let samples = |value: fn(&MemProfileRecord) -> isize| {
+        sites
+            .iter()
+            .map(|(name, rec)| (Some(("site", *name)), value(rec) as i64))
+            .collect::<Vec<_>>()
+    };
+    write_family(
+        writer,
+        "heappy_site_allocated_objects",
+        "counter",
+        "Sampled objects allocated by the call site.",
+        &samples(|rec| rec.alloc_objects),
+    )?;
+    write_family(
+        writer,
+        "heappy_site_allocated_bytes",
+        "counter",
+        "Sampled bytes allocated by the call site.",
+        &samples(|rec| rec.alloc_bytes),
+    )?;
+    #[cfg(feature = "measure_free")]
+    write_family(
+        writer,
+        "heappy_site_in_use_bytes",
+        "gauge",
+        "Sampled bytes allocated by the call site and not yet freed.",
+        &samples(|rec| rec.in_use_bytes()),
+    )?;
+    Ok(())
+}
+
+fn site_name(frames: &pprof::Frames) -> String {
+    let symbols = stack_symbols(frames)
+        .into_iter()
+        .map(|symbol| symbol.name())
+        .collect::<Vec<_>>();
+    symbols
+        .iter()
+        .find(|name| !HOOKS.contains(&name.as_str()) && !is_skipped(name))
+        .or_else(|| symbols.first())
+        .cloned()
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+// Tells whether a function belongs to a skipped crate, e.g. `alloc::vec::Vec<T>::push`, or implements one of its
+// traits, e.g. `<u8 as alloc::vec::spec_from_elem::SpecFromElem>::from_elem`: the caller makes a better label.
+fn is_skipped(name: &str) -> bool {
+    let name = name.trim_start_matches('<');
+    let (ty, tr) = name.split_once(" as ").unwrap_or((name, ""));
+    SKIPPED_CRATES
+        .iter()
+        .any(|prefix| ty.starts_with(prefix) || tr.starts_with(prefix))
+}
+
+// Writes a metric family. The samples of counters get the mandatory `_total` suffix, and the families whose name
+// ends with `_bytes` are annotated with their unit.
+fn write_family<W: Write>(
+    writer: &mut W,
+    name: &str,
+    ty: &str,
+    help: &str,
+    samples: &[(Option<(&str, &str)>, i64)],
+) -> std::io::Result<()> {
+    writeln!(writer, "# TYPE {} {}", name, ty)?;
+    if name.ends_with("_bytes") {
+        writeln!(writer, "# UNIT {} bytes", name)?;
+    }
+    writeln!(writer, "# HELP {} {}", name, help)?;
+    let suffix = if ty == "counter" { "_total" } else { "" };
+    for (label, value) in samples {
+        match label {
+            Some((key, label_value)) => writeln!(
+                writer,
+                "{}{}{{{}=\"{}\"}} {}",
+                name,
+                suffix,
+                key,
+                escape(label_value),
+                value
+            )?,
+            None => writeln!(writer, "{}{} {}", name, suffix, value)?,
+        }
+    }
+    Ok(())
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_is_skipped() {
+        assert!(is_skipped("alloc::vec::Vec<T>::push"));
+        assert!(is_skipped(
+            "<alloc::vec::Vec<T> as core::clone::Clone>::clone"
+        ));
+        assert!(is_skipped(
+            "<u8 as alloc::vec::spec_from_elem::SpecFromElem>::from_elem"
+        ));
+        assert!(is_skipped("<my::Type as core::clone::Clone>::clone"));
+        assert!(!is_skipped("my::function"));
+    }
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(escape("a\"b\\c\nd"), "a\\\"b\\\\c\\nd");
+    }
+
+    #[cfg(feature = "enable_heap_profiler")]
+    #[test]
+    fn test_openmetrics() {
+        let report = crate::profiler::test::profile(|| {
+            std::hint::black_box(vec![0u8; 1 << 20]);
+            std::hint::black_box(vec![0u8; 2 << 20]);
+        });
+
+        let mut text = vec![];
+        write_openmetrics(&mut text, Some(&report), 0).unwrap();
+        let text = String::from_utf8(text).unwrap();
+        assert!(text.contains("# TYPE heappy_allocated_bytes counter\n"));
+        assert!(text.contains("heappy_allocated_bytes_total "));
+        assert!(text.contains("heappy_site_allocated_bytes_total{site=\"other\"} "));
+        assert!(!text.contains("test_openmetrics"));
+        assert!(text.ends_with("# EOF\n"));
+
+        let mut text = vec![];
+        write_openmetrics(&mut text, Some(&report), 10).unwrap();
+        let text = String::from_utf8(text).unwrap();
+        assert!(text.contains(
+            "heappy_site_allocated_bytes_total{site=\"heappy::metrics::test::test_openmetrics"
+        ));
+    }
+}
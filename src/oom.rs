@@ -0,0 +1,95 @@
+//! Dump the heap profile when an allocation fails, so that out of memory crashes leave some data behind.
+
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use crate::forbid::write_stderr;
+use crate::profiler::Profiler;
+use crate::{HeapReport, Metric};
+
+pub(crate) const DEFAULT_EMERGENCY_RESERVE: usize = 4 << 20;
+
+struct OomDump {
+    path: PathBuf,
+    // released right before dumping, to give the allocator some room to work with.
+    reserve: Vec<u8>,
+}
+
+static OOM_DUMP: Mutex<Option<OomDump>> = Mutex::new(None);
+
+// Arms the dump, reserving the emergency buffer.
+pub(crate) fn arm(path: PathBuf, reserve: usize) {
+    Profiler::untracked(|| {
+        // touch the reserve so that it's actually backed by memory.
+        let reserve = vec![1u8; reserve];
+        *OOM_DUMP.lock().unwrap_or_else(|e| e.into_inner()) = Some(OomDump { path, reserve });
+    });
+}
+
+pub(crate) fn disarm() {
+    let dump = Profiler::untracked(|| OOM_DUMP.lock().unwrap_or_else(|e| e.into_inner()).take());
+    std::mem::drop(dump);
+}
+
+// Called by the allocation hooks when an allocation of `size` bytes fails. The dump is written only once.
+#[cfg_attr(not(feature = "enable_heap_profiler"), allow(dead_code))]
+pub(crate) fn alloc_failed(size: usize) {
+    Profiler::non_reentrant(|| {
+        let Some(dump) = OOM_DUMP.try_lock().ok().and_then(|mut dump| dump.take()) else {
+            return;
+        };
+        let OomDump { path, reserve } = dump;
+        std::mem::drop(reserve);
+
+        write_stderr(&format!(
+            "heappy: allocation of {} bytes failed, writing heap profile to {}\n",
+            size,
+            path.display()
+        ));
+        let report = HeapReport::snapshot();
+        let mut folded_path = path.clone().into_os_string();
+        folded_path.push(".folded");
+
+        let res = std::fs::File::create(&path)
+            .and_then(|mut file| report.write_pprof(&mut file))
+            .and_then(|_| std::fs::File::create(&folded_path))
+            .and_then(|file| {
+                let mut writer = std::io::BufWriter::new(file);
+                report.write_folded(&mut writer, Metric::AllocBytes)?;
+                writer.flush()
+            });
+        if let Err(err) = res {
+            write_stderr(&format!("heappy: cannot write heap profile: {}\n", err));
+        }
+    });
+}
+
+#[cfg(all(test, feature = "enable_heap_profiler"))]
+mod test {
+    use crate::profiler::test::serial;
+    use crate::HeapProfilerGuardBuilder;
+
+    #[test]
+    fn test_dump_on_alloc_failure() {
+        let _serial = serial();
+        let path = std::env::temp_dir().join(format!("heappy-oom-{}.pb", std::process::id()));
+        let folded_path = path.with_extension("pb.folded");
+
+        let guard = HeapProfilerGuardBuilder::default()
+            .period(1)
+            .dump_on_alloc_failure(&path)
+            .emergency_reserve(1 << 20)
+            .build()
+            .unwrap();
+        std::hint::black_box(vec![0u8; 1 << 20]);
+        assert!(Vec::<u8>::new().try_reserve(1 << 62).is_err());
+        drop(guard);
+
+        assert!(std::fs::metadata(&path).unwrap().len() > 0);
+        let folded = std::fs::read_to_string(&folded_path).unwrap();
+        assert!(folded.contains("test_dump_on_alloc_failure"), "{}", folded);
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&folded_path).unwrap();
+    }
+}
@@ -3,9 +3,10 @@ use std::collections::{HashMap, HashSet};
 use std::hash::{Hash, Hasher};
 use std::io::Write;
 use std::mem::MaybeUninit;
+use std::ops::{Bound, RangeBounds};
 use std::sync::{
     atomic::{AtomicBool, Ordering},
-    Mutex, MutexGuard,
+    Mutex, MutexGuard, TryLockError,
 };
 use std::time::SystemTime;
 
@@ -15,11 +16,28 @@ use spin::RwLock;
 use thiserror::Error;
 
 use crate::collector;
+use crate::forbid;
+#[cfg(feature = "measure_free")]
+use crate::limit::SoftLimit;
+use crate::oom;
+use crate::{FlamegraphOptions, HeapSample, HeapTotals};
 
 const MAX_DEPTH: usize = 32;
 
+// Sampling period used when none is configured explicitly, the same as Go's default `runtime.MemProfileRate`.
+const DEFAULT_PERIOD: usize = 512 * 1024;
+
 static HEAP_PROFILER_ENABLED: AtomicBool = AtomicBool::new(false);
 
+// set when an allocation makes the live bytes cross the soft limit, see `Profiler::fire_soft_limit`.
+#[cfg(feature = "measure_free")]
+static SOFT_LIMIT_CROSSED: AtomicBool = AtomicBool::new(false);
+
+thread_local! {
+    // set while the current thread is handling an allocation event, see `Profiler::untracked`.
+    static ENTERED: Cell<bool> = Cell::new(false);
+}
+
 lazy_static::lazy_static! {
     static ref HEAP_PROFILER_STATE: RwLock<ProfilerState<MAX_DEPTH>> = RwLock::new(Default::default());
     static ref HEAP_PROFILER_ENTER: Mutex<()> = Mutex::new(());
@@ -29,6 +47,12 @@ lazy_static::lazy_static! {
 pub enum Error {
     #[error("attempting to run a heap profiler while the another heap profiler is being run")]
     ConcurrentHeapProfiler,
+    #[error("attempting to inject allocation failures while another fault injection is active")]
+    ConcurrentFaultInjection,
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error("cannot render flamegraph: {0}")]
+    Flamegraph(String),
 }
 
 pub type Result<T, E = Error> = std::result::Result<T, E>;
@@ -40,29 +64,211 @@ pub struct HeapProfilerGuard {
 
 impl HeapProfilerGuard {
     pub fn new(period: usize) -> Result<Self> {
-        let guard = HEAP_PROFILER_ENTER
-            .try_lock()
-            .map_err(|_| Error::ConcurrentHeapProfiler)?;
-        Profiler::start(period);
-        Ok(Self { _guard: guard })
+        HeapProfilerGuardBuilder::default().period(period).build()
     }
 
     pub fn report(self) -> HeapReport {
         std::mem::drop(self);
         HeapReport::new()
     }
+
+    /// Returns a report of what has been collected so far, while the profiler keeps running. Like [`Self::report`],
+    /// it includes the remote frees, the peak and the leaks tracked so far.
+    pub fn snapshot(&self) -> HeapReport {
+        HeapReport::snapshot()
+    }
+
+    /// Keeps profiling until the process exits, then writes a report of the sampled allocations that have never
+    /// been freed to `path`, like gperftools' heap checker.
+    #[cfg(feature = "measure_free")]
+    pub fn report_leaks_at_exit<P: Into<std::path::PathBuf>>(self, path: P) {
+        static REGISTER: std::sync::Once = std::sync::Once::new();
+
+        HEAP_PROFILER_STATE.write().report_leaks = true;
+        *LEAK_REPORT_PATH.lock().unwrap_or_else(|e| e.into_inner()) = Some(path.into());
+        // the profiler must keep running (and other profilers kept out) until the process exits.
+        std::mem::forget(self);
+
+        REGISTER.call_once(|| unsafe {
+            libc::atexit(write_leak_report_at_exit);
+        });
+    }
+}
+
+#[cfg(feature = "measure_free")]
+static LEAK_REPORT_PATH: Mutex<Option<std::path::PathBuf>> = Mutex::new(None);
+
+#[cfg(feature = "measure_free")]
+extern "C" fn write_leak_report_at_exit() {
+    let Some(path) = LEAK_REPORT_PATH.lock().ok().and_then(|mut path| path.take()) else {
+        return;
+    };
+    Profiler::stop();
+    let report = HeapReport::new();
+
+    let res = std::fs::File::create(&path).and_then(|mut file| report.write_leak_report(&mut file));
+    if let Err(err) = res {
+        eprintln!(
+            "heappy: cannot write leak report to {}: {}",
+            path.display(),
+            err
+        );
+    }
 }
 
 impl Drop for HeapProfilerGuard {
     fn drop(&mut self) {
         Profiler::stop();
+        oom::disarm();
+    }
+}
+
+/// Configures and starts a heap profiler session.
+#[derive(Debug, Clone)]
+pub struct HeapProfilerGuardBuilder {
+    period: usize,
+    min_size: usize,
+    max_size: usize,
+    #[cfg(feature = "measure_free")]
+    peak_hysteresis: Option<usize>,
+    #[cfg(feature = "measure_free")]
+    report_leaks: bool,
+    #[cfg(feature = "measure_free")]
+    soft_limit: Option<SoftLimit>,
+    #[cfg(feature = "measure_free")]
+    track_frees: bool,
+    current_thread_only: bool,
+    oom_dump_path: Option<std::path::PathBuf>,
+    emergency_reserve: usize,
+}
+
+impl Default for HeapProfilerGuardBuilder {
+    fn default() -> Self {
+        Self {
+            period: DEFAULT_PERIOD,
+            min_size: 0,
+            max_size: usize::MAX,
+            #[cfg(feature = "measure_free")]
+            peak_hysteresis: None,
+            #[cfg(feature = "measure_free")]
+            report_leaks: false,
+            #[cfg(feature = "measure_free")]
+            soft_limit: None,
+            #[cfg(feature = "measure_free")]
+            track_frees: true,
+            current_thread_only: false,
+            oom_dump_path: None,
+            emergency_reserve: oom::DEFAULT_EMERGENCY_RESERVE,
+        }
+    }
+}
+
+impl HeapProfilerGuardBuilder {
+    /// Take a sample every `period` allocated bytes. A period of 1 records every allocation.
+    pub fn period(mut self, period: usize) -> Self {
+        self.period = period;
+        self
+    }
+
+    /// Only track allocations whose size falls within `range`, e.g. `1 << 20..` to find big buffers.
+    /// Allocations outside the range don't count towards the sampling period either. A realloc is tracked if the
+    /// resized block falls within the range.
+    pub fn size_range<R: RangeBounds<usize>>(mut self, range: R) -> Self {
+        self.min_size = match range.start_bound() {
+            Bound::Included(&n) => n,
+            Bound::Excluded(&n) => n.saturating_add(1),
+            Bound::Unbounded => 0,
+        };
+        self.max_size = match range.end_bound() {
+            Bound::Included(&n) => n,
+            Bound::Excluded(&n) => n.saturating_sub(1),
+            Bound::Unbounded => usize::MAX,
+        };
+        self
+    }
+
+    /// Keep a copy of the in-use profile taken when the live bytes reached their highest value, see
+    /// [`HeapReport::peak`]. A new copy is taken only once the live bytes exceed the previous peak by `hysteresis`
+    /// bytes, which bounds the cost of tracking a steadily growing heap.
+    #[cfg(feature = "measure_free")]
+    pub fn track_peak(mut self, hysteresis: usize) -> Self {
+        self.peak_hysteresis = Some(hysteresis);
+        self
+    }
+
+    /// Include the sampled allocations that haven't been freed by the time the report is taken, see
+    /// [`HeapReport::leaks`].
+    #[cfg(feature = "measure_free")]
+    pub fn report_leaks(mut self, enabled: bool) -> Self {
+        self.report_leaks = enabled;
+        self
+    }
+
+    /// Act when the live bytes tracked by the profiler cross a soft limit, see [`SoftLimit`].
+    #[cfg(feature = "measure_free")]
+    pub fn soft_limit(mut self, soft_limit: SoftLimit) -> Self {
+        self.soft_limit = Some(soft_limit);
+        self
+    }
+
+    /// Track frees, which is the default. Without them the profiler only reports allocations and costs less: there are
+    /// no in-use figures, lifetimes, realloc chains, peaks, leaks nor soft limit.
+    #[cfg(feature = "measure_free")]
+    pub fn track_frees(mut self, enabled: bool) -> Self {
+        self.track_frees = enabled;
+        self
+    }
+
+    /// Only track the allocations (and frees) performed by the thread that builds the profiler.
+    pub fn current_thread_only(mut self) -> Self {
+        self.current_thread_only = true;
+        self
+    }
+
+    /// When an allocation fails, write the profile collected so far to `path` (pprof) and to `path` with a `.folded`
+    /// suffix (collapsed stacks), before returning the failure to the caller. An emergency buffer reserved when the
+    /// profiler starts is released right before writing, to leave the allocator some room to do so.
+    pub fn dump_on_alloc_failure<P: Into<std::path::PathBuf>>(mut self, path: P) -> Self {
+        self.oom_dump_path = Some(path.into());
+        self
+    }
+
+    /// Size of the emergency buffer used by [`dump_on_alloc_failure`](Self::dump_on_alloc_failure).
+    pub fn emergency_reserve(mut self, bytes: usize) -> Self {
+        self.emergency_reserve = bytes;
+        self
+    }
+
+    pub fn build(self) -> Result<HeapProfilerGuard> {
+        let guard = match HEAP_PROFILER_ENTER.try_lock() {
+            Ok(guard) => guard,
+            // a panic while profiling (e.g. a failed assertion in a test) doesn't leave the profiler unusable.
+            Err(TryLockError::Poisoned(err)) => err.into_inner(),
+            Err(TryLockError::WouldBlock) => return Err(Error::ConcurrentHeapProfiler),
+        };
+        Profiler::start(&self);
+        Ok(HeapProfilerGuard { _guard: guard })
+    }
+
+    // Like `build` but waits up to `timeout` for any other running profiler to be stopped. A profiler may run until
+    // the process exits, e.g. when started from `HEAPPY_CONF`, so it doesn't wait forever.
+    pub(crate) fn build_waiting(self, timeout: std::time::Duration) -> Result<HeapProfilerGuard> {
+        let deadline = std::time::Instant::now() + timeout;
+        loop {
+            match self.clone().build() {
+                Err(Error::ConcurrentHeapProfiler) if std::time::Instant::now() < deadline => {
+                    std::thread::sleep(std::time::Duration::from_millis(1));
+                }
+                res => return res,
+            }
+        }
     }
 }
 
 pub struct Profiler;
 
 impl Profiler {
-    fn enabled() -> bool {
+    pub(crate) fn enabled() -> bool {
         HEAP_PROFILER_ENABLED.load(Ordering::SeqCst)
     }
 
@@ -70,11 +276,15 @@ impl Profiler {
         HEAP_PROFILER_ENABLED.store(value, Ordering::SeqCst)
     }
 
-    fn start(period: usize) {
+    fn start(options: &HeapProfilerGuardBuilder) {
         let mut profiler = HEAP_PROFILER_STATE.write();
-        *profiler = ProfilerState::new(period);
+        *profiler = ProfilerState::with_options(options);
         std::mem::drop(profiler);
 
+        if let Some(path) = &options.oom_dump_path {
+            oom::arm(path.clone(), options.emergency_reserve);
+        }
+
         Self::set_enabled(true);
     }
 
@@ -82,113 +292,449 @@ impl Profiler {
         Self::set_enabled(false);
     }
 
-    // Called by malloc hooks to record a memory allocation event.
-    pub(crate) unsafe fn track_allocated(size: isize) {
-        thread_local!(static ENTERED: Cell<bool> = Cell::new(false));
-
-        struct ResetOnDrop;
+    // Runs `f` without tracking the allocations it performs, e.g. because they're performed by the profiler itself.
+    pub(crate) fn untracked<F, R>(f: F) -> R
+    where
+        F: FnOnce() -> R,
+    {
+        struct RestoreOnDrop(bool);
 
-        impl Drop for ResetOnDrop {
+        impl Drop for RestoreOnDrop {
             fn drop(&mut self) {
-                ENTERED.with(|b| b.set(false));
+                ENTERED.with(|b| b.set(self.0));
             }
         }
 
-        if !ENTERED.with(|b| b.replace(true)) {
-            let _reset_on_drop = ResetOnDrop;
-            if Self::enabled() {
-                let mut profiler = HEAP_PROFILER_STATE.write();
-                let mut sample_now = false;
-                match size.cmp(&0) {
-                    std::cmp::Ordering::Greater => {
-                        profiler.allocated_objects += 1;
-                        profiler.allocated_bytes += size;
-
-                        if profiler.allocated_bytes >= profiler.next_sample {
-                            profiler.next_sample =
-                                profiler.allocated_bytes + profiler.period as isize;
-                            sample_now = true;
-                        }
-                    }
-                    #[cfg(not(feature = "measure_free"))]
-                    std::cmp::Ordering::Less => {
-                        // ignore
-                    }
-                    #[cfg(feature = "measure_free")]
-                    std::cmp::Ordering::Less => {
-                        profiler.freed_objects += 1;
-                        profiler.freed_bytes += -size;
-
-                        if profiler.freed_bytes >= profiler.next_free_sample {
-                            profiler.next_free_sample =
-                                profiler.freed_bytes + profiler.period as isize;
-                            sample_now = true;
-                        }
-                    }
-                    std::cmp::Ordering::Equal => {
-                        // ignore
-                    }
-                }
+        let _restore_on_drop = RestoreOnDrop(ENTERED.with(|b| b.replace(true)));
+        f()
+    }
 
-                if sample_now {
-                    let mut bt = Frames::new();
-                    // we're already holding a lock
-                    backtrace::trace_unsynchronized(|frame| bt.push(frame));
+    // Runs `f` unless we're being called recursively, i.e. by an allocation performed while handling another one.
+    // This way the allocations performed by the profiler itself are not tracked.
+    pub(crate) fn non_reentrant<F, R>(f: F) -> Option<R>
+    where
+        F: FnOnce() -> R,
+    {
+        if ENTERED.with(|b| b.get()) {
+            None
+        } else {
+            Some(Self::untracked(f))
+        }
+    }
 
-                    profiler.collector.record(bt, size);
-                }
+    // Runs `f` on the profiler state while the profiler is enabled.
+    unsafe fn with_state<F, R>(f: F) -> Option<R>
+    where
+        F: FnOnce(&mut ProfilerState<MAX_DEPTH>) -> R,
+    {
+        if Self::enabled() {
+            let mut profiler = HEAP_PROFILER_STATE.write();
+            if profiler
+                .thread_id
+                .map_or(true, |thread_id| thread_id == current_thread_id())
+            {
+                return Some(f(&mut profiler));
             }
         }
+        None
+    }
+
+    // Called by malloc hooks to record a memory allocation event.
+    // `ptr` is the address of the allocated (or freed) block, `size` is the usable size of the allocation (negative
+    // for frees) and `requested` the number of bytes the caller asked for, which is only meaningful for allocations.
+    #[cfg_attr(not(feature = "enable_heap_profiler"), allow(dead_code))]
+    pub(crate) unsafe fn track_allocated(ptr: usize, size: isize, requested: isize) {
+        Self::non_reentrant(|| {
+            if size > 0 {
+                forbid::check_allocation(size);
+            }
+            Self::with_state(|profiler| {
+                profiler.track(ptr, size, size.unsigned_abs(), requested, true)
+            });
+        });
+        #[cfg(feature = "measure_free")]
+        Self::fire_soft_limit();
+    }
+
+    // Called by the realloc hook to record that the block at `old_ptr` of `old_size` usable bytes is now at `new_ptr`
+    // and has `new_size` usable bytes. Only the size delta is sampled, at the reallocating call site.
+    #[cfg_attr(not(feature = "enable_heap_profiler"), allow(dead_code))]
+    pub(crate) unsafe fn track_reallocated(
+        old_ptr: usize,
+        new_ptr: usize,
+        old_size: isize,
+        new_size: isize,
+        requested: isize,
+    ) {
+        Self::non_reentrant(|| {
+            if new_size > old_size || new_ptr != old_ptr {
+                forbid::check_allocation(new_size);
+            }
+            Self::with_state(|profiler| {
+                #[cfg(feature = "measure_free")]
+                profiler.track_realloc_chain(old_ptr, new_ptr, old_size, new_size);
+                #[cfg(not(feature = "measure_free"))]
+                let _ = old_ptr;
+
+                // the requested delta is relative to the old usable size, so that the slack is the one of the new block.
+                profiler.track(
+                    new_ptr,
+                    new_size - old_size,
+                    new_size as usize,
+                    requested - old_size,
+                    false,
+                );
+            });
+        });
+        #[cfg(feature = "measure_free")]
+        Self::fire_soft_limit();
+    }
+
+    // Invokes the soft limit actions if the last tracked allocation crossed the limit. The actions run outside of
+    // the profiler lock and of `non_reentrant`, so that the memory freed by the callback is accounted for. The limit
+    // is not fired while handling another event, e.g. from an allocation of the profiler itself; it is then fired by
+    // the next allocation that is tracked.
+    #[cfg(feature = "measure_free")]
+    unsafe fn fire_soft_limit() {
+        if !SOFT_LIMIT_CROSSED.load(Ordering::Relaxed) || ENTERED.with(|b| b.get()) {
+            return;
+        }
+        // cloning the soft limit allocates, which must not be tracked while holding the profiler lock.
+        let crossed = Self::untracked(|| {
+            Self::with_state(|profiler| {
+                SOFT_LIMIT_CROSSED.store(false, Ordering::Relaxed);
+                profiler
+                    .soft_limit
+                    .clone()
+                    .map(|soft_limit| (soft_limit, profiler.live_bytes()))
+            })
+        });
+        if let Some((soft_limit, live_bytes)) = crossed.flatten() {
+            soft_limit.fire(live_bytes);
+        }
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct HeapReport {
     data: HashMap<pprof::Frames, collector::MemProfileRecord>,
+    // objects and bytes freed by a thread other than the allocating one, by allocating and freeing stack.
+    #[cfg(feature = "measure_free")]
+    remote_frees: HashMap<(pprof::Frames, pprof::Frames), (isize, isize)>,
+    #[cfg(feature = "measure_free")]
+    peak: Option<Box<HeapPeak>>,
+    #[cfg(feature = "measure_free")]
+    leaks: Option<Vec<HeapLeak>>,
+    // all the allocations tracked during the session, sampled or not.
+    allocated_objects: isize,
+    allocated_bytes: isize,
     period: usize,
 }
 
+/// Sampled allocations made at the same call site which haven't been freed by the time the report was taken.
+#[cfg(feature = "measure_free")]
+#[derive(Debug, Clone)]
+pub struct HeapLeak {
+    pub frames: pprof::Frames,
+    pub objects: isize,
+    pub bytes: isize,
+    /// Age of the oldest allocation.
+    pub max_age: std::time::Duration,
+}
+
+/// The in-use profile captured when the live bytes reached their highest value, see
+/// [`HeapProfilerGuardBuilder::track_peak`].
+#[cfg(feature = "measure_free")]
+#[derive(Debug, Clone)]
+pub struct HeapPeak {
+    pub timestamp: SystemTime,
+    /// Bytes allocated since the profiler has been started, minus the bytes freed since then (see
+    /// [`SoftLimitEvent::live_bytes`](crate::SoftLimitEvent::live_bytes)).
+    pub bytes: isize,
+    /// The sampled allocations that were live at the peak, reported as allocations.
+    pub report: HeapReport,
+}
+
 impl HeapReport {
     fn new() -> Self {
         let mut profiler = HEAP_PROFILER_STATE.write();
         let collector = std::mem::take(&mut profiler.collector);
 
-        let data = collector
+        Self {
+            #[cfg(feature = "measure_free")]
+            remote_frees: symbolize_remote_frees(std::mem::take(&mut profiler.remote_frees)),
+            #[cfg(feature = "measure_free")]
+            peak: profiler
+                .peak
+                .take()
+                .map(|peak| Self::from_peak(peak, profiler.period)),
+            #[cfg(feature = "measure_free")]
+            leaks: profiler
+                .report_leaks
+                .then(|| symbolize_leaks(profiler.live_sites())),
+            allocated_objects: profiler.allocated_objects,
+            allocated_bytes: profiler.allocated_bytes,
+            ..Self::from_collector(collector, profiler.period)
+        }
+    }
+
+    // Takes a report of the running profiler without resetting it. The backtraces are symbolized once the profiler
+    // lock is released.
+    pub(crate) fn snapshot() -> Self {
+        Profiler::untracked(|| {
+            let profiler = HEAP_PROFILER_STATE.read();
+            let collector = profiler.collector.clone();
+            #[cfg(feature = "measure_free")]
+            let remote_frees = profiler.remote_frees.clone();
+            #[cfg(feature = "measure_free")]
+            let peak = profiler.peak.clone();
+            #[cfg(feature = "measure_free")]
+            let leaks = profiler.report_leaks.then(|| profiler.live_sites());
+            let (period, allocated_objects, allocated_bytes) = (
+                profiler.period,
+                profiler.allocated_objects,
+                profiler.allocated_bytes,
+            );
+            std::mem::drop(profiler);
+
+            Self {
+                #[cfg(feature = "measure_free")]
+                remote_frees: symbolize_remote_frees(remote_frees),
+                #[cfg(feature = "measure_free")]
+                peak: peak.map(|peak| Self::from_peak(peak, period)),
+                #[cfg(feature = "measure_free")]
+                leaks: leaks.map(symbolize_leaks),
+                allocated_objects,
+                allocated_bytes,
+                ..Self::from_collector(collector, period)
+            }
+        })
+    }
+
+    /// Combines reports, e.g. from several sessions or processes, into one. Call sites are matched on their
+    /// symbolized stacks, so that reports from different runs of the same binary line up, whatever the allocating
+    /// thread. The sampling period of the result is the largest one of the reports; peaks and leaks are not carried
+    /// over.
+    pub fn merge<'a, I>(reports: I) -> Self
+    where
+        I: IntoIterator<Item = &'a HeapReport>,
+    {
+        Self::combine(reports.into_iter().map(|report| (report, 1)))
+    }
+
+    /// Returns the per call site difference between this report and `base`, matching call sites like
+    /// [`HeapReport::merge`]. The values are negative where `base` allocated more; the writers report them as is,
+    /// except for the flamegraphs which leave them out (see [`HeapReport::diff_flamegraph`] to plot both sides).
+    /// The call sites which are the same in both reports are left out.
+    pub fn diff(&self, base: &HeapReport) -> Self {
+        let mut diff = Self::combine([(self, 1), (base, -1)]);
+        diff.data.retain(|_, rec| {
+            *rec != collector::MemProfileRecord {
+                threads: rec.threads,
+                ..Default::default()
+            }
+        });
+        diff
+    }
+
+    // Sums up the reports, each multiplied by the given factor, matching call sites on their symbolized stacks.
+    fn combine<'a, I>(reports: I) -> Self
+    where
+        I: IntoIterator<Item = (&'a HeapReport, isize)>,
+    {
+        let mut combined = Self::from_collector(collector::Collector::<Frames<0>>::new(), 0);
+        let mut data: HashMap<Vec<String>, (pprof::Frames, collector::MemProfileRecord)> =
+            HashMap::new();
+        #[cfg(feature = "measure_free")]
+        let mut remote_frees = HashMap::new();
+        for (report, factor) in reports {
+            combined.period = combined.period.max(report.period);
+            combined.allocated_objects += report.allocated_objects * factor;
+            combined.allocated_bytes += report.allocated_bytes * factor;
+            for (frames, rec) in report.data.iter() {
+                data.entry(stack_key(frames))
+                    .or_insert_with(|| (frames.clone(), Default::default()))
+                    .1
+                    .merge_scaled(rec, factor);
+            }
+            #[cfg(feature = "measure_free")]
+            for ((alloc, free), (objects, bytes)) in report.remote_frees.iter() {
+                let (_, counts) = remote_frees
+                    .entry((stack_key(alloc), stack_key(free)))
+                    .or_insert_with(|| ((alloc.clone(), free.clone()), (0, 0)));
+                counts.0 += objects * factor;
+                counts.1 += bytes * factor;
+            }
+        }
+        // the remote frees are matched with their allocation site by frames, which must then be the combined ones.
+        #[cfg(feature = "measure_free")]
+        {
+            combined.remote_frees = remote_frees
+                .into_iter()
+                .map(|((alloc_key, _), ((alloc, free), counts))| {
+                    let alloc = data
+                        .get(&alloc_key)
+                        .map_or(alloc, |(frames, _)| frames.clone());
+                    ((alloc, free), counts)
+                })
+                .collect();
+        }
+        combined.data = data.into_values().collect();
+        combined
+    }
+
+    fn from_collector<const N: usize>(
+        collector: collector::Collector<Frames<N>>,
+        period: usize,
+    ) -> Self {
+        let data: HashMap<pprof::Frames, collector::MemProfileRecord> = collector
             .into_iter()
             .map(|(frames, rec)| (frames.into(), rec))
             .collect();
         Self {
+            allocated_objects: data.values().map(|rec| rec.alloc_objects).sum(),
+            allocated_bytes: data.values().map(|rec| rec.alloc_bytes).sum(),
             data,
-            period: profiler.period,
+            #[cfg(feature = "measure_free")]
+            remote_frees: HashMap::new(),
+            #[cfg(feature = "measure_free")]
+            peak: None,
+            #[cfg(feature = "measure_free")]
+            leaks: None,
+            period,
+        }
+    }
+
+    #[cfg(feature = "measure_free")]
+    fn from_peak<const N: usize>(peak: Peak<N>, period: usize) -> Box<HeapPeak> {
+        Box::new(HeapPeak {
+            timestamp: peak.timestamp,
+            bytes: peak.bytes,
+            report: Self::from_collector(peak.collector, period),
+        })
+    }
+
+    // Returns the objects and bytes allocated during the session, including the ones that weren't sampled.
+    pub(crate) fn allocated(&self) -> (isize, isize) {
+        (self.allocated_objects, self.allocated_bytes)
+    }
+
+    pub(crate) fn period(&self) -> usize {
+        self.period
+    }
+
+    pub(crate) fn records(
+        &self,
+    ) -> impl Iterator<Item = (&pprof::Frames, &collector::MemProfileRecord)> {
+        self.data.iter()
+    }
+
+    /// Iterates over the call sites of the report, in no particular order.
+    pub fn samples(&self) -> impl Iterator<Item = HeapSample<'_>> {
+        self.data
+            .iter()
+            .map(|(frames, rec)| HeapSample::new(frames, rec))
+    }
+
+    /// Returns the totals of the report.
+    pub fn totals(&self) -> HeapTotals {
+        let mut sampled = collector::MemProfileRecord::default();
+        for rec in self.data.values() {
+            sampled.merge(rec);
+        }
+        HeapTotals {
+            sampled,
+            allocated_objects: self.allocated_objects,
+            allocated_bytes: self.allocated_bytes,
+            period: self.period,
         }
     }
 
-    /// flamegraph will write an svg flamegraph into writer.
+    /// Returns the profile of the sampled allocations that were live when the heap peaked, if peak tracking was
+    /// enabled with [`HeapProfilerGuardBuilder::track_peak`].
+    #[cfg(feature = "measure_free")]
+    pub fn peak(&self) -> Option<&HeapPeak> {
+        self.peak.as_deref()
+    }
+
+    /// Returns the sampled allocations which were never freed during the session, grouped by call site and ordered
+    /// by bytes, if enabled with [`HeapProfilerGuardBuilder::report_leaks`].
+    #[cfg(feature = "measure_free")]
+    pub fn leaks(&self) -> Option<&[HeapLeak]> {
+        self.leaks.as_deref()
+    }
+
+    /// flamegraph will write an svg flamegraph of the allocated bytes into writer.
+    /// Panics if the flamegraph cannot be rendered, e.g. when nothing was sampled; see
+    /// [`HeapReport::flamegraph_with_options`].
     pub fn flamegraph<W>(&self, writer: W)
     where
         W: Write,
     {
-        // the pprof crate already has all the necessary plumbing for the embedded flamegraph library, let's just render
-        // the alloc_bytes stat with it.
-        let data = self
-            .data
-            .iter()
-            .map(|(frames, rec)| (frames.clone(), rec.alloc_bytes))
-            .collect();
-
-        let timing = Default::default();
+        self.flamegraph_with_options(writer, &Default::default())
+            .unwrap();
+    }
 
-        let report = pprof::Report { data, timing };
+    /// Writes an svg flamegraph of the metric chosen in `options` into writer. Call sites with a negative value are
+    /// left out.
+    pub fn flamegraph_with_options<W>(&self, writer: W, options: &FlamegraphOptions) -> Result<()>
+    where
+        W: Write,
+    {
+        let lines = self
+            .folded_stacks(options.metric)
+            .into_iter()
+            .filter(|(_, value)| *value > 0)
+            .map(|(stack, value)| format!("{} {}", stack, value))
+            .collect::<Vec<_>>();
+        crate::flamegraph::render(
+            lines.iter().map(String::as_str),
+            &mut options.inferno_options(),
+            writer,
+        )
+    }
 
-        let mut options: pprof::flamegraph::Options = Default::default();
+    /// Writes a differential flamegraph of the allocated bytes into writer: the frames are as wide as in this report
+    /// and colored red where more was allocated than in `baseline`, blue where less was. The baseline is scaled to the
+    /// same total first, so that reports covering different durations or loads can be compared.
+    pub fn diff_flamegraph<W>(&self, baseline: &HeapReport, writer: W) -> Result<()>
+    where
+        W: Write,
+    {
+        let metric = collector::Metric::AllocBytes;
+        let before = baseline.folded_stacks(metric);
+        let after = self.folded_stacks(metric);
 
-        options.count_name = "bytes".to_string();
-        options.colors =
-            pprof::flamegraph::color::Palette::Basic(pprof::flamegraph::color::BasicPalette::Mem);
+        let total = |stacks: &std::collections::BTreeMap<String, isize>| {
+            stacks.values().map(|v| (*v).max(0) as f64).sum::<f64>()
+        };
+        let (total_before, total_after) = (total(&before), total(&after));
+        let scale = if total_before > 0.0 && total_after > 0.0 {
+            total_after / total_before
+        } else {
+            1.0
+        };
 
-        report
-            .flamegraph_with_options(writer, &mut options)
-            .unwrap();
+        let mut stacks = std::collections::BTreeMap::new();
+        for (stack, value) in before {
+            stacks.entry(stack).or_insert((0, 0)).0 =
+                (value.max(0) as f64 * scale).round() as usize;
+        }
+        for (stack, value) in after {
+            stacks.entry(stack).or_insert((0, 0)).1 = value.max(0) as usize;
+        }
+        let lines = stacks
+            .into_iter()
+            .filter(|(_, (before, after))| *before > 0 || *after > 0)
+            .map(|(stack, (before, after))| format!("{} {} {}", stack, before, after))
+            .collect::<Vec<_>>();
+        crate::flamegraph::render(
+            lines.iter().map(String::as_str),
+            &mut FlamegraphOptions::default()
+                .metric(metric)
+                .inferno_options(),
+            writer,
+        )
     }
 
     fn inner_pprof(&self) -> pprof::protos::Profile {
@@ -196,7 +742,7 @@ impl HeapReport {
         let data = self.data.clone();
 
         let mut dudup_str = HashSet::new();
-        for key in data.iter().map(|(key, _)| key) {
+        for key in data.keys() {
             for frame in key.frames.iter() {
                 for symbol in frame {
                     dudup_str.insert(symbol.name());
@@ -265,12 +811,21 @@ impl HeapReport {
                     rec.free_bytes as i64,
                     rec.in_use_objects() as i64,
                     rec.in_use_bytes() as i64,
+                    rec.requested_bytes as i64,
+                    rec.slack_bytes() as i64,
+                    rec.remote_free_objects as i64,
+                    rec.remote_free_bytes as i64,
                 ],
                 #[cfg(not(feature = "measure_free"))]
-                value: vec![rec.alloc_objects as i64, rec.alloc_bytes as i64],
+                value: vec![
+                    rec.alloc_objects as i64,
+                    rec.alloc_bytes as i64,
+                    rec.requested_bytes as i64,
+                    rec.slack_bytes() as i64,
+                ],
                 ..protos::Sample::default()
             };
-            samples.push(sample);
+            samples.push((sample, rec.alloc_sizes));
         }
 
         let mut push_string = |s: &str| {
@@ -279,6 +834,26 @@ impl HeapReport {
             idx as i64
         };
 
+        // Expose the size histogram of each call site as one numeric label per non-empty size class,
+        // carrying the number of objects allocated in that class.
+        let mut size_class_keys = HashMap::new();
+        let samples = samples
+            .into_iter()
+            .map(|(mut sample, sizes)| {
+                for (class, count) in sizes.iter() {
+                    let key = *size_class_keys
+                        .entry(class)
+                        .or_insert_with(|| push_string(&size_class_label(class)));
+                    sample.label.push(protos::Label {
+                        key,
+                        num: count as i64,
+                        ..protos::Label::default()
+                    });
+                }
+                sample
+            })
+            .collect::<Vec<_>>();
+
         let alloc_objects_idx = push_string("alloc_objects");
         let count_idx = push_string("count");
         let alloc_space_idx = push_string("alloc_space");
@@ -291,6 +866,12 @@ impl HeapReport {
         let inuse_objects_idx = push_string("inuse_objects");
         #[cfg(feature = "measure_free")]
         let inuse_space_idx = push_string("inuse_space");
+        let requested_space_idx = push_string("requested_space");
+        let slack_space_idx = push_string("slack_space");
+        #[cfg(feature = "measure_free")]
+        let remote_free_objects_idx = push_string("remote_free_objects");
+        #[cfg(feature = "measure_free")]
+        let remote_free_space_idx = push_string("remote_free_space");
         let space_idx = push_string("space");
 
         let sample_type = vec![
@@ -322,6 +903,24 @@ impl HeapReport {
                 ty: inuse_space_idx,
                 unit: bytes_idx,
             },
+            protos::ValueType {
+                ty: requested_space_idx,
+                unit: bytes_idx,
+            },
+            protos::ValueType {
+                ty: slack_space_idx,
+                unit: bytes_idx,
+            },
+            #[cfg(feature = "measure_free")]
+            protos::ValueType {
+                ty: remote_free_objects_idx,
+                unit: count_idx,
+            },
+            #[cfg(feature = "measure_free")]
+            protos::ValueType {
+                ty: remote_free_space_idx,
+                unit: bytes_idx,
+            },
         ];
 
         let period_type = Some(pprof::protos::ValueType {
@@ -349,7 +948,7 @@ impl HeapReport {
         let drop_frames_idx = proto.string_table.len();
         proto
             .string_table
-            .push(".*::Profiler::track_allocated".to_string());
+            .push(".*::Profiler::track_[a-z]+".to_string());
         proto.drop_frames = drop_frames_idx as i64;
 
         proto
@@ -360,6 +959,261 @@ impl HeapReport {
         self.pprof().encode(&mut buf)?;
         writer.write_all(&buf)
     }
+
+    /// Writes a human readable report of the allocation size histogram of every call site,
+    /// ordered by allocated bytes.
+    pub fn write_size_histograms<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        let mut entries = self.data.iter().collect::<Vec<_>>();
+        entries.sort_by_key(|(_, rec)| std::cmp::Reverse(rec.alloc_bytes));
+
+        for (frames, rec) in entries {
+            if rec.alloc_objects == 0 {
+                continue;
+            }
+            writeln!(
+                writer,
+                "{} bytes in {} objects allocated at:",
+                rec.alloc_bytes, rec.alloc_objects
+            )?;
+            write_stack(writer, frames, 4)?;
+
+            let max = rec.alloc_sizes.iter().map(|(_, count)| count).max();
+            for (class, count) in rec.alloc_sizes.iter() {
+                let lower = collector::SizeHistogram::lower_bound(class);
+                let upper = lower
+                    .checked_mul(2)
+                    .map(format_size)
+                    .unwrap_or_else(|| "inf".to_string());
+                let bar = (count * HISTOGRAM_WIDTH / max.unwrap_or(1)).max(1) as usize;
+                writeln!(
+                    writer,
+                    "  [{:>9}, {:>9}) {:>10} {}",
+                    format_size(lower),
+                    upper,
+                    count,
+                    "#".repeat(bar)
+                )?;
+            }
+            writeln!(writer)?;
+        }
+        Ok(())
+    }
+
+    /// Writes a report of the "short-lived churn" call sites: the sites whose sampled allocations were freed
+    /// within `max_lifetime`, ordered by the bytes freed that quickly, along with their lifetime histogram.
+    #[cfg(feature = "measure_free")]
+    pub fn write_churn_report<W: Write>(
+        &self,
+        writer: &mut W,
+        max_lifetime: std::time::Duration,
+    ) -> std::io::Result<()> {
+        let mut entries = self
+            .data
+            .iter()
+            .map(|(frames, rec)| (frames, rec, rec.lifetimes.freed_within(max_lifetime)))
+            .filter(|(_, _, (objects, _))| *objects > 0)
+            .collect::<Vec<_>>();
+        entries.sort_by_key(|(_, _, (_, bytes))| std::cmp::Reverse(*bytes));
+
+        for (frames, rec, (objects, bytes)) in entries {
+            writeln!(
+                writer,
+                "{} bytes in {} objects freed within {:?}, allocated at:",
+                bytes, objects, max_lifetime
+            )?;
+            write_stack(writer, frames, 4)?;
+            for (class, objects, bytes) in rec.lifetimes.iter() {
+                writeln!(
+                    writer,
+                    "  >= {:>12?} {:>10} objects {:>12} bytes",
+                    collector::LifetimeHistogram::lower_bound(class),
+                    objects,
+                    bytes
+                )?;
+            }
+            writeln!(writer)?;
+        }
+        Ok(())
+    }
+
+    /// Writes a report of the call sites whose sampled allocations have been repeatedly grown by realloc,
+    /// ordered by number of grow steps. Sites showing up here are candidates for `with_capacity`.
+    #[cfg(feature = "measure_free")]
+    pub fn write_realloc_report<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        let mut entries = self
+            .data
+            .iter()
+            .filter(|(_, rec)| rec.realloc_chains > 0)
+            .collect::<Vec<_>>();
+        entries.sort_by_key(|(_, rec)| std::cmp::Reverse(rec.realloc_grow_steps));
+
+        for (frames, rec) in entries {
+            writeln!(
+                writer,
+                "{} grow steps in {} realloc chains, {} bytes copied, {} bytes average final size, allocated at:",
+                rec.realloc_grow_steps,
+                rec.realloc_chains,
+                rec.realloc_copied_bytes,
+                rec.realloc_final_bytes / rec.realloc_chains
+            )?;
+            write_stack(writer, frames, 4)?;
+            writeln!(writer)?;
+        }
+        Ok(())
+    }
+
+    /// Writes a report of the call sites whose sampled allocations have been freed by a thread other than the
+    /// allocating one, ordered by remotely freed bytes, along with the stacks that freed them.
+    #[cfg(feature = "measure_free")]
+    pub fn write_remote_free_report<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        let mut entries = self
+            .data
+            .iter()
+            .filter(|(_, rec)| rec.remote_free_objects > 0)
+            .collect::<Vec<_>>();
+        entries.sort_by_key(|(_, rec)| std::cmp::Reverse(rec.remote_free_bytes));
+
+        for (frames, rec) in entries {
+            writeln!(
+                writer,
+                "{} bytes in {} objects freed by another thread, allocated at:",
+                rec.remote_free_bytes, rec.remote_free_objects
+            )?;
+            write_stack(writer, frames, 4)?;
+
+            // the frames of the samples of a call site differ by their timestamp, so match them by stack instead.
+            let key = stack_key(frames);
+            let mut frees = self
+                .remote_frees
+                .iter()
+                .filter(|((alloc, _), _)| stack_key(alloc) == key)
+                .collect::<Vec<_>>();
+            frees.sort_by_key(|(_, (_, bytes))| std::cmp::Reverse(*bytes));
+            for ((_, free), (objects, bytes)) in frees {
+                writeln!(writer, "  {} bytes in {} objects freed at:", bytes, objects)?;
+                write_stack(writer, free, 6)?;
+            }
+            writeln!(writer)?;
+        }
+        Ok(())
+    }
+
+    /// Writes a report of the sampled allocations which were never freed during the session, see
+    /// [`HeapReport::leaks`].
+    #[cfg(feature = "measure_free")]
+    pub fn write_leak_report<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        let leaks = self.leaks().unwrap_or_default();
+        let bytes: isize = leaks.iter().map(|leak| leak.bytes).sum();
+        let objects: isize = leaks.iter().map(|leak| leak.objects).sum();
+        writeln!(
+            writer,
+            "Leak check found {} leaked bytes in {} objects (sampled every {} bytes)",
+            bytes, objects, self.period
+        )?;
+        writeln!(writer)?;
+
+        for leak in leaks {
+            writeln!(
+                writer,
+                "{} bytes in {} objects, oldest allocated {:?} ago, allocated at:",
+                leak.bytes, leak.objects, leak.max_age
+            )?;
+            write_stack(writer, &leak.frames, 4)?;
+            writeln!(writer)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the `metric` of every call site as collapsed stacks (`root;caller;callee 1234`), the input format of
+    /// inferno, speedscope and Brendan Gregg's flamegraph scripts. Stacks are sorted and call sites with a zero value
+    /// are left out.
+    pub fn write_folded<W: Write>(
+        &self,
+        writer: &mut W,
+        metric: collector::Metric,
+    ) -> std::io::Result<()> {
+        for (stack, value) in self.folded_stacks(metric) {
+            if value != 0 {
+                writeln!(writer, "{} {}", stack, value)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Returns the `metric` of every call site by collapsed stack, in stack order. Distinct backtraces can resolve to
+    // the same symbols, their values are summed up.
+    fn folded_stacks(
+        &self,
+        metric: collector::Metric,
+    ) -> std::collections::BTreeMap<String, isize> {
+        let mut stacks = std::collections::BTreeMap::new();
+        for (frames, rec) in self.data.iter() {
+            *stacks.entry(folded_stack(frames)).or_insert(0) += metric.value(rec);
+        }
+        stacks
+    }
+}
+
+// Returns the symbols of a backtrace root first, separated by semicolons.
+fn folded_stack(frames: &pprof::Frames) -> String {
+    let symbols = stack_symbols(frames);
+    let names = symbols.iter().rev().map(|symbol| symbol.to_string());
+    names.collect::<Vec<_>>().join(";")
+}
+
+// Writes the symbols of a backtrace, one per line, leaving out the frames of the profiler itself.
+fn write_stack<W: Write>(
+    writer: &mut W,
+    frames: &pprof::Frames,
+    indent: usize,
+) -> std::io::Result<()> {
+    for symbol in stack_symbols(frames) {
+        writeln!(writer, "{:indent$}{}", "", symbol, indent = indent)?;
+    }
+    Ok(())
+}
+
+// Returns the symbols of a backtrace, leaf first, leaving out the frames of the profiler itself.
+pub(crate) fn stack_symbols(frames: &pprof::Frames) -> Vec<&pprof::Symbol> {
+    let symbols = frames.frames.iter().flatten().collect::<Vec<_>>();
+    let skip = symbols
+        .iter()
+        .rposition(|symbol| symbol.name().contains("::Profiler::track_"))
+        .map_or(0, |pos| pos + 1);
+    symbols[skip..].to_vec()
+}
+
+// Identifies a call site across reports: unlike `pprof::Frames` equality, it ignores the sample timestamp.
+fn stack_key(frames: &pprof::Frames) -> Vec<String> {
+    frames
+        .frames
+        .iter()
+        .flatten()
+        .map(|symbol| symbol.name())
+        .collect()
+}
+
+// Returns the key of the pprof label carrying the number of objects allocated in a size class.
+pub(crate) fn size_class_label(class: usize) -> String {
+    format!(
+        "alloc_size_{}",
+        format_size(collector::SizeHistogram::lower_bound(class))
+    )
+}
+
+// Width of the largest bar in the size histogram text report.
+const HISTOGRAM_WIDTH: isize = 40;
+
+// Formats a byte count using binary units, e.g. `64KiB`.
+fn format_size(bytes: usize) -> String {
+    const UNITS: [&str; 7] = ["B", "KiB", "MiB", "GiB", "TiB", "PiB", "EiB"];
+    let mut unit = 0;
+    let mut value = bytes;
+    while value >= 1024 && value % 1024 == 0 && unit < UNITS.len() - 1 {
+        value /= 1024;
+        unit += 1;
+    }
+    format!("{}{}", value, UNITS[unit])
 }
 
 // Current profiler state, collection of sampled frames.
@@ -378,9 +1232,288 @@ struct ProfilerState<const N: usize> {
     next_free_sample: isize,
     // take a sample every period bytes.
     period: usize,
+    // only allocations whose size is within [min_size, max_size] are tracked.
+    min_size: usize,
+    max_size: usize,
+    // sampled allocations that haven't been freed yet, by address.
+    #[cfg(feature = "measure_free")]
+    live: HashMap<usize, LiveAllocation<N>>,
+    // objects and bytes of sampled allocations freed by a thread other than the allocating one,
+    // by allocating and freeing backtrace.
+    #[cfg(feature = "measure_free")]
+    remote_frees: HashMap<(Frames<N>, Frames<N>), (isize, isize)>,
+    // take a new snapshot of the live allocations when the live bytes exceed the last peak by this many bytes.
+    #[cfg(feature = "measure_free")]
+    peak_hysteresis: Option<usize>,
+    #[cfg(feature = "measure_free")]
+    peak: Option<Peak<N>>,
+    // whether reports should include the sampled allocations that are still live.
+    #[cfg(feature = "measure_free")]
+    report_leaks: bool,
+    // when set, only the allocations performed by this thread are tracked.
+    thread_id: Option<u64>,
+    #[cfg(feature = "measure_free")]
+    soft_limit: Option<SoftLimit>,
+    // set once the live bytes cross the soft limit, until they get back enough below it.
+    #[cfg(feature = "measure_free")]
+    soft_limit_exceeded: bool,
+    // when unset, frees (and shrinking reallocs) are ignored as if the measure_free feature was disabled.
+    #[cfg(feature = "measure_free")]
+    track_frees: bool,
+}
+
+// The sampled live allocations at the time the live bytes peaked.
+#[cfg(feature = "measure_free")]
+#[derive(Clone)]
+struct Peak<const N: usize> {
+    timestamp: SystemTime,
+    bytes: isize,
+    collector: collector::Collector<Frames<N>>,
+}
+
+// A sampled allocation which is still live. The allocation timestamp and thread are the ones of its backtrace.
+#[cfg(feature = "measure_free")]
+struct LiveAllocation<const N: usize> {
+    frames: Frames<N>,
+    size: isize,
+    // number of times the block has been moved or resized by realloc.
+    reallocs: usize,
+}
+
+/// Returns a process unique identifier of the current thread, the one reported by [`HeapSample::thread`]. Identifiers
+/// are assigned in the order the threads first allocate (or call this function) and are never 0.
+pub fn current_thread_id() -> u64 {
+    // unlike `std::thread::current()` it's safe to call from the allocator hooks, even while the thread local storage
+    // of the thread is being torn down.
+    static NEXT_THREAD_ID: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(1);
+    thread_local!(static THREAD_ID: Cell<u64> = Cell::new(0));
+
+    THREAD_ID.with(|id| {
+        if id.get() == 0 {
+            id.set(NEXT_THREAD_ID.fetch_add(1, Ordering::Relaxed));
+        }
+        id.get()
+    })
 }
 
 impl<const N: usize> ProfilerState<N> {
+    fn with_options(options: &HeapProfilerGuardBuilder) -> Self {
+        Self {
+            min_size: options.min_size,
+            max_size: options.max_size,
+            #[cfg(feature = "measure_free")]
+            peak_hysteresis: options.peak_hysteresis,
+            #[cfg(feature = "measure_free")]
+            report_leaks: options.report_leaks,
+            thread_id: options.current_thread_only.then(current_thread_id),
+            #[cfg(feature = "measure_free")]
+            soft_limit: options.soft_limit.clone(),
+            #[cfg(feature = "measure_free")]
+            track_frees: options.track_frees,
+            ..Self::new(options.period)
+        }
+    }
+
+    fn accepts_size(&self, size: usize) -> bool {
+        (self.min_size..=self.max_size).contains(&size)
+    }
+
+    // Accounts for an allocation (or a free, if `size` is negative) of the block at `ptr` and samples it when the
+    // period elapses. `block_size` is the size of the block, checked against the size range: it differs from `size`
+    // for the size deltas caused by a realloc. `live` tells whether the event changes the liveness of the block,
+    // which is not the case for those deltas either.
+    #[cfg_attr(not(feature = "measure_free"), allow(unused_variables))]
+    unsafe fn track(
+        &mut self,
+        ptr: usize,
+        size: isize,
+        block_size: usize,
+        requested: isize,
+        live: bool,
+    ) {
+        // a block allocated within the size range may have been resized out of it by realloc: it must no longer be
+        // live once freed all the same, so the size range only applies to the counters and to the sampling.
+        #[cfg(feature = "measure_free")]
+        if size < 0 && live {
+            if let Some(live) = self.live.remove(&ptr) {
+                self.track_live_freed(live);
+            }
+        }
+        if !self.accepts_size(block_size) {
+            return;
+        }
+        #[cfg(feature = "measure_free")]
+        if size < 0 && !self.track_frees {
+            return;
+        }
+        let mut sample_now = false;
+        match size.cmp(&0) {
+            std::cmp::Ordering::Greater => {
+                self.allocated_objects += 1;
+                self.allocated_bytes += size;
+
+                if self.allocated_bytes >= self.next_sample {
+                    self.next_sample = self.allocated_bytes + self.period as isize;
+                    sample_now = true;
+                }
+            }
+            #[cfg(not(feature = "measure_free"))]
+            std::cmp::Ordering::Less => {
+                // ignore
+            }
+            #[cfg(feature = "measure_free")]
+            std::cmp::Ordering::Less => {
+                self.freed_objects += 1;
+                self.freed_bytes += -size;
+
+                if self.freed_bytes >= self.next_free_sample {
+                    self.next_free_sample = self.freed_bytes + self.period as isize;
+                    sample_now = true;
+                }
+            }
+            std::cmp::Ordering::Equal => {
+                // ignore
+            }
+        }
+
+        if sample_now {
+            let mut bt = Frames::new();
+            // we're already holding a lock
+            backtrace::trace_unsynchronized(|frame| bt.push(frame));
+
+            #[cfg(feature = "measure_free")]
+            if live && size > 0 && self.track_frees {
+                let frames = bt.clone();
+                self.live.insert(
+                    ptr,
+                    LiveAllocation {
+                        frames,
+                        size,
+                        reallocs: 0,
+                    },
+                );
+            }
+            let thread_id = bt.thread_id;
+            self.collector
+                .record(bt, size, block_size, requested, thread_id);
+        }
+
+        #[cfg(feature = "measure_free")]
+        if size > 0 {
+            self.track_peak();
+        }
+        #[cfg(feature = "measure_free")]
+        self.track_soft_limit();
+    }
+
+    // Flags the crossing of the soft limit. To avoid firing repeatedly while hovering around the limit, the limit is
+    // re-armed only once the live bytes drop below 90% of it.
+    #[cfg(feature = "measure_free")]
+    fn track_soft_limit(&mut self) {
+        let Some(soft_limit) = &self.soft_limit else {
+            return;
+        };
+        let live_bytes = self.live_bytes();
+        let limit = soft_limit.bytes() as isize;
+        if !self.soft_limit_exceeded && live_bytes >= limit {
+            self.soft_limit_exceeded = true;
+            SOFT_LIMIT_CROSSED.store(true, Ordering::Relaxed);
+        } else if self.soft_limit_exceeded && live_bytes < limit - limit / 10 {
+            self.soft_limit_exceeded = false;
+        }
+    }
+
+    // Bytes allocated since the profiler has been started, minus the bytes freed since then. Frees of blocks allocated
+    // before the profiler started count too, so this can undercount, and even go negative, in a running process.
+    #[cfg(feature = "measure_free")]
+    fn live_bytes(&self) -> isize {
+        self.allocated_bytes - self.freed_bytes
+    }
+
+    // Takes a snapshot of the sampled live allocations when the live bytes reach a new peak.
+    #[cfg(feature = "measure_free")]
+    fn track_peak(&mut self) {
+        let Some(hysteresis) = self.peak_hysteresis else {
+            return;
+        };
+        let live_bytes = self.live_bytes();
+        let threshold = self.peak.as_ref().map_or(0, |peak| peak.bytes) + hysteresis as isize;
+        if live_bytes < threshold {
+            return;
+        }
+
+        let mut collector = collector::Collector::new();
+        for live in self.live.values() {
+            collector.record(
+                live.frames.clone(),
+                live.size,
+                live.size as usize,
+                live.size,
+                live.frames.thread_id,
+            );
+        }
+        self.peak = Some(Peak {
+            timestamp: SystemTime::now(),
+            bytes: live_bytes,
+            collector,
+        });
+    }
+
+    // Accounts for the free of a sampled allocation: its lifetime and whether it has been freed by another thread.
+    #[cfg(feature = "measure_free")]
+    unsafe fn track_live_freed(&mut self, live: LiveAllocation<N>) {
+        let lifetime = SystemTime::now()
+            .duration_since(live.frames.ts)
+            .unwrap_or_default();
+        if live.frames.thread_id != current_thread_id() {
+            let mut bt = Frames::new();
+            // we're already holding a lock
+            backtrace::trace_unsynchronized(|frame| bt.push(frame));
+
+            let (objects, bytes) = self
+                .remote_frees
+                .entry((live.frames.clone(), bt))
+                .or_default();
+            *objects += 1;
+            *bytes += live.size;
+            self.collector
+                .record_remote_free(live.frames.clone(), live.size);
+        }
+        self.collector
+            .record_lifetime(live.frames, live.size, lifetime);
+    }
+
+    // Follows a sampled allocation across a realloc, attributing the grow steps and the bytes copied to the call site
+    // that originally allocated the block.
+    #[cfg(feature = "measure_free")]
+    fn track_realloc_chain(
+        &mut self,
+        old_ptr: usize,
+        new_ptr: usize,
+        old_size: isize,
+        new_size: isize,
+    ) {
+        let Some(mut live) = self.live.remove(&old_ptr) else {
+            return;
+        };
+        let copied = if new_ptr != old_ptr {
+            old_size.min(new_size)
+        } else {
+            0
+        };
+        let prev_size = if live.reallocs == 0 { 0 } else { old_size };
+        self.collector.record_realloc(
+            live.frames.clone(),
+            new_size > old_size,
+            live.reallocs == 0,
+            copied,
+            new_size - prev_size,
+        );
+        live.reallocs += 1;
+        live.size = new_size;
+        self.live.insert(new_ptr, live);
+    }
+
     fn new(period: usize) -> Self {
         Self {
             collector: collector::Collector::new(),
@@ -394,20 +1527,85 @@ impl<const N: usize> ProfilerState<N> {
             next_sample: period as isize,
             #[cfg(feature = "measure_free")]
             next_free_sample: period as isize,
+            min_size: 0,
+            max_size: usize::MAX,
+            #[cfg(feature = "measure_free")]
+            live: HashMap::new(),
+            #[cfg(feature = "measure_free")]
+            remote_frees: HashMap::new(),
+            #[cfg(feature = "measure_free")]
+            peak_hysteresis: None,
+            #[cfg(feature = "measure_free")]
+            peak: None,
+            #[cfg(feature = "measure_free")]
+            report_leaks: false,
+            thread_id: None,
+            #[cfg(feature = "measure_free")]
+            soft_limit: None,
+            #[cfg(feature = "measure_free")]
+            soft_limit_exceeded: false,
+            #[cfg(feature = "measure_free")]
+            track_frees: true,
+        }
+    }
+
+    // Groups the sampled allocations that are still live by allocating backtrace, see `symbolize_leaks`.
+    #[cfg(feature = "measure_free")]
+    fn live_sites(&self) -> HashMap<Frames<N>, (isize, isize, std::time::Duration)> {
+        let now = SystemTime::now();
+        let mut sites: HashMap<Frames<N>, (isize, isize, std::time::Duration)> = HashMap::new();
+        for live in self.live.values() {
+            let age = now.duration_since(live.frames.ts).unwrap_or_default();
+            let (objects, bytes, max_age) = sites.entry(live.frames.clone()).or_default();
+            *objects += 1;
+            *bytes += live.size;
+            *max_age = (*max_age).max(age);
         }
+        sites
     }
 }
 
+// Turns the objects, bytes and max age of the live sampled allocations by backtrace into leaks, ordered by bytes.
+#[cfg(feature = "measure_free")]
+fn symbolize_leaks<const N: usize>(
+    sites: HashMap<Frames<N>, (isize, isize, std::time::Duration)>,
+) -> Vec<HeapLeak> {
+    let mut leaks = sites
+        .into_iter()
+        .map(|(frames, (objects, bytes, max_age))| HeapLeak {
+            frames: frames.into(),
+            objects,
+            bytes,
+            max_age,
+        })
+        .collect::<Vec<_>>();
+    leaks.sort_by_key(|leak| std::cmp::Reverse(leak.bytes));
+    leaks
+}
+
+#[cfg(feature = "measure_free")]
+fn symbolize_remote_frees<const N: usize>(
+    remote_frees: HashMap<(Frames<N>, Frames<N>), (isize, isize)>,
+) -> HashMap<(pprof::Frames, pprof::Frames), (isize, isize)> {
+    remote_frees
+        .into_iter()
+        .map(|((alloc, free), counts)| ((alloc.into(), free.into()), counts))
+        .collect()
+}
+
 impl<const N: usize> Default for ProfilerState<N> {
     fn default() -> Self {
         Self::new(1)
     }
 }
 
+// A backtrace, along with the time it has been taken and the thread it has been taken by. Call sites are told apart
+// by the frames only, the threads are recorded in their `MemProfileRecord`.
 struct Frames<const N: usize> {
     frames: [MaybeUninit<Frame>; N],
     size: usize,
     ts: SystemTime,
+    thread_id: u64,
 }
 
 impl<const N: usize> Clone for Frames<N> {
@@ -418,6 +1616,7 @@ impl<const N: usize> Clone for Frames<N> {
         }
         n.size = self.size;
         n.ts = self.ts;
+        n.thread_id = self.thread_id;
         n
     }
 }
@@ -428,6 +1627,7 @@ impl<const N: usize> Frames<N> {
             frames: std::array::from_fn(|_| MaybeUninit::uninit()),
             size: 0,
             ts: SystemTime::now(),
+            thread_id: current_thread_id(),
         }
     }
 
@@ -506,11 +1706,37 @@ impl<const N: usize> From<Frames<N>> for pprof::Frames {
 }
 
 #[cfg(test)]
-mod test {
+pub(crate) mod test {
     use super::*;
 
+    // The profiler is a process wide singleton, serialize the tests that start it.
+    static SERIAL: Mutex<()> = Mutex::new(());
+
+    pub(crate) fn serial() -> MutexGuard<'static, ()> {
+        SERIAL.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    // The profiler options of most tests: every allocation of at least 1MiB is sampled, which leaves out the small
+    // allocations of the test harness.
+    #[cfg_attr(not(feature = "enable_heap_profiler"), allow(dead_code))]
+    pub(crate) fn test_profiler() -> HeapProfilerGuardBuilder {
+        HeapProfilerGuardBuilder::default()
+            .period(1)
+            .size_range(1 << 20..)
+    }
+
+    // Runs `f` under a `test_profiler` and returns the report.
+    #[cfg_attr(not(feature = "enable_heap_profiler"), allow(dead_code))]
+    pub(crate) fn profile<F: FnOnce()>(f: F) -> HeapReport {
+        let _serial = serial();
+        let guard = test_profiler().build().unwrap();
+        f();
+        guard.report()
+    }
+
     #[test]
     fn test_reentrant() {
+        let _serial = serial();
         let _guard = HeapProfilerGuard::new(1).unwrap();
 
         assert!(matches!(
@@ -518,4 +1744,390 @@ mod test {
             Err(Error::ConcurrentHeapProfiler)
         ));
     }
+
+    #[test]
+    fn test_build_waiting() {
+        let _serial = serial();
+        let guard = HeapProfilerGuard::new(1).unwrap();
+        assert!(matches!(
+            HeapProfilerGuardBuilder::default().build_waiting(std::time::Duration::from_millis(10)),
+            Err(Error::ConcurrentHeapProfiler)
+        ));
+
+        let waiting = std::thread::spawn(|| {
+            HeapProfilerGuardBuilder::default()
+                .build_waiting(std::time::Duration::from_secs(10))
+                .is_ok()
+        });
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        drop(guard);
+        assert!(waiting.join().unwrap());
+    }
+
+    #[cfg(feature = "enable_heap_profiler")]
+    #[test]
+    fn test_size_range() {
+        let report = profile(|| {
+            std::hint::black_box(vec![0u8; 1024]);
+            std::hint::black_box(vec![0u8; 3 << 20]);
+        });
+
+        let total: isize = report.data.values().map(|rec| rec.alloc_bytes).sum();
+        assert!(total >= 3 << 20);
+        for rec in report.data.values() {
+            assert!(rec
+                .alloc_sizes
+                .iter()
+                .all(|(class, _)| class >= collector::SizeHistogram::size_class(1 << 20)));
+        }
+
+        let mut text = vec![];
+        report.write_size_histograms(&mut text).unwrap();
+        assert!(String::from_utf8(text)
+            .unwrap()
+            .contains("[     2MiB,      4MiB)"));
+    }
+
+    #[cfg(feature = "enable_heap_profiler")]
+    #[test]
+    fn test_size_range_realloc() {
+        let mut v = Vec::<u8>::with_capacity(1 << 20);
+        let report = profile(move || {
+            // the resized block is within the range, the size delta is not.
+            v.reserve_exact((1 << 20) + 4096);
+            std::hint::black_box(v);
+        });
+
+        let total: isize = report.data.values().map(|rec| rec.alloc_bytes).sum();
+        assert!((4096..1 << 20).contains(&total), "{}", total);
+        // the size histogram counts the resized block.
+        for rec in report.data.values() {
+            assert!(rec
+                .alloc_sizes
+                .iter()
+                .all(|(class, _)| class >= collector::SizeHistogram::size_class(1 << 20)));
+        }
+    }
+
+    #[cfg(all(feature = "enable_heap_profiler", feature = "measure_free"))]
+    #[test]
+    fn test_churn_report() {
+        let report = profile(|| {
+            for _ in 0..10 {
+                std::hint::black_box(vec![0u8; 1 << 20]);
+            }
+        });
+
+        let mut text = vec![];
+        report
+            .write_churn_report(&mut text, std::time::Duration::from_secs(1))
+            .unwrap();
+        let text = String::from_utf8(text).unwrap();
+        assert!(text.contains("10485760 bytes in 10 objects freed within"));
+    }
+
+    #[cfg(all(feature = "enable_heap_profiler", feature = "measure_free"))]
+    #[test]
+    fn test_realloc_report() {
+        let _serial = serial();
+        let guard = HeapProfilerGuardBuilder::default()
+            .period(1)
+            .size_range(1 << 16..)
+            .build()
+            .unwrap();
+        let mut v = Vec::<u8>::with_capacity(1 << 16);
+        for _ in 0..4 {
+            v.reserve_exact(v.capacity() * 2 - v.len());
+            v.resize(v.capacity(), 0);
+        }
+        std::hint::black_box(v);
+        let report = guard.report();
+
+        let chains = report
+            .data
+            .values()
+            .filter(|rec| rec.realloc_chains > 0)
+            .collect::<Vec<_>>();
+        assert_eq!(chains.len(), 1);
+        assert_eq!(chains[0].realloc_grow_steps, 4);
+        assert_eq!(chains[0].realloc_final_bytes, 1 << 20);
+
+        let mut text = vec![];
+        report.write_realloc_report(&mut text).unwrap();
+        assert!(String::from_utf8(text)
+            .unwrap()
+            .starts_with("4 grow steps in 1 realloc chains"));
+    }
+
+    #[cfg(all(feature = "enable_heap_profiler", feature = "measure_free"))]
+    #[test]
+    fn test_remote_free_report() {
+        let report = profile(|| {
+            let v = vec![0u8; 1 << 20];
+            std::thread::spawn(move || drop(v)).join().unwrap();
+            assert_eq!(HeapReport::snapshot().remote_frees.len(), 1);
+        });
+
+        let remote: isize = report.data.values().map(|rec| rec.remote_free_bytes).sum();
+        assert_eq!(remote, 1 << 20);
+        assert_eq!(report.remote_frees.len(), 1);
+
+        let mut text = vec![];
+        report.write_remote_free_report(&mut text).unwrap();
+        assert!(String::from_utf8(text)
+            .unwrap()
+            .starts_with("1048576 bytes in 1 objects freed by another thread"));
+    }
+
+    #[cfg(all(feature = "enable_heap_profiler", feature = "measure_free"))]
+    #[test]
+    fn test_remote_free_report_many_samples() {
+        let report = profile(|| {
+            let vs = (0..2).map(|_| vec![0u8; 1 << 20]).collect::<Vec<_>>();
+            // free the samples in the opposite order, so that the first one recorded isn't the first one freed.
+            std::thread::spawn(move || vs.into_iter().rev().for_each(drop))
+                .join()
+                .unwrap();
+        });
+
+        let mut text = vec![];
+        report.write_remote_free_report(&mut text).unwrap();
+        let text = String::from_utf8(text).unwrap();
+        assert!(text.starts_with("2097152 bytes in 2 objects freed by another thread"));
+        assert!(text.contains("  2097152 bytes in 2 objects freed at:"));
+    }
+
+    #[cfg(all(feature = "enable_heap_profiler", feature = "measure_free"))]
+    #[test]
+    fn test_peak() {
+        let _serial = serial();
+        let guard = test_profiler().track_peak(1 << 20).build().unwrap();
+        let a = vec![0u8; 4 << 20];
+        let b = vec![0u8; 4 << 20];
+        drop(a);
+        drop(b);
+        std::hint::black_box(vec![0u8; 2 << 20]);
+        assert_eq!(
+            guard.snapshot().peak().map(|peak| peak.bytes),
+            Some(8 << 20)
+        );
+        let report = guard.report();
+
+        let peak = report.peak().unwrap();
+        assert_eq!(peak.bytes, 8 << 20);
+        let live: isize = peak.report.data.values().map(|rec| rec.alloc_bytes).sum();
+        assert_eq!(live, 8 << 20);
+    }
+
+    #[cfg(all(feature = "enable_heap_profiler", feature = "measure_free"))]
+    #[test]
+    fn test_leaks() {
+        let _serial = serial();
+        let guard = test_profiler().report_leaks(true).build().unwrap();
+        std::hint::black_box(vec![0u8; 1 << 20]);
+        let leaked = std::hint::black_box(vec![0u8; 2 << 20]).leak();
+        assert_eq!(guard.snapshot().leaks().map(<[_]>::len), Some(1));
+        let report = guard.report();
+
+        let leaks = report.leaks().unwrap();
+        assert_eq!(leaks.len(), 1);
+        assert_eq!((leaks[0].objects, leaks[0].bytes), (1, 2 << 20));
+
+        let mut text = vec![];
+        report.write_leak_report(&mut text).unwrap();
+        assert!(String::from_utf8(text)
+            .unwrap()
+            .starts_with("Leak check found 2097152 leaked bytes in 1 objects"));
+
+        unsafe { drop(Box::from_raw(leaked)) };
+    }
+
+    #[cfg(all(feature = "enable_heap_profiler", feature = "measure_free"))]
+    #[test]
+    fn test_leaks_resized_out_of_range() {
+        let _serial = serial();
+        let guard = test_profiler().report_leaks(true).build().unwrap();
+        let mut v = std::hint::black_box(vec![0u8; 1 << 20]);
+        // shrinking the block below the size range must not keep it live once freed.
+        v.truncate(16);
+        v.shrink_to_fit();
+        drop(std::hint::black_box(v));
+        let report = guard.report();
+
+        assert_eq!(report.leaks().map(<[_]>::len), Some(0));
+    }
+
+    #[cfg(feature = "enable_heap_profiler")]
+    #[test]
+    fn test_write_folded() {
+        let report = profile(|| {
+            std::hint::black_box(vec![0u8; 1 << 20]);
+        });
+
+        let folded = |metric| {
+            let mut text = vec![];
+            report.write_folded(&mut text, metric).unwrap();
+            String::from_utf8(text).unwrap()
+        };
+        let bytes = folded(collector::Metric::AllocBytes);
+        let line = bytes
+            .lines()
+            .find(|line| line.contains("test_write_folded"))
+            .unwrap();
+        assert!(line.ends_with(" 1048576"), "{}", line);
+        assert!(!line.contains("Profiler::track_"));
+        assert!(folded(collector::Metric::AllocObjects)
+            .lines()
+            .any(|line| line.contains("test_write_folded") && line.ends_with(" 1")));
+    }
+
+    #[cfg(feature = "enable_heap_profiler")]
+    #[test]
+    fn test_flamegraph_with_options() {
+        let report = profile(|| {
+            std::hint::black_box(vec![0u8; 1 << 20]);
+        });
+
+        let options = FlamegraphOptions::default()
+            .metric(collector::Metric::AllocObjects)
+            .title("objects")
+            .inverted(true)
+            .reverse_stack_order(true);
+        let mut svg = vec![];
+        report.flamegraph_with_options(&mut svg, &options).unwrap();
+        let svg = String::from_utf8(svg).unwrap();
+        assert!(svg.contains("<svg"));
+        assert!(svg.contains("objects"));
+
+        // nothing to plot.
+        let empty = HeapReport::merge(std::iter::empty::<&HeapReport>());
+        assert!(matches!(
+            empty.flamegraph_with_options(vec![], &Default::default()),
+            Err(Error::Flamegraph(_))
+        ));
+    }
+
+    #[cfg(feature = "enable_heap_profiler")]
+    #[test]
+    fn test_diff_flamegraph() {
+        let mut baseline = None;
+        let report = profile(|| {
+            std::hint::black_box(vec![0u8; 1 << 20]);
+            baseline = Some(HeapReport::snapshot());
+            std::hint::black_box(vec![0u8; 1 << 21]);
+        });
+        let baseline = baseline.unwrap();
+
+        let mut svg = vec![];
+        report.diff_flamegraph(&baseline, &mut svg).unwrap();
+        let svg = String::from_utf8(svg).unwrap();
+        assert!(svg.contains("<svg"));
+        assert!(svg.contains("test_diff_flamegraph"));
+    }
+
+    #[cfg(feature = "enable_heap_profiler")]
+    #[test]
+    fn test_merge_diff() {
+        let report = profile(|| {
+            std::hint::black_box(vec![0u8; 1 << 20]);
+        });
+        let bytes = |report: &HeapReport| -> isize {
+            report
+                .records()
+                .filter(|(frames, _)| folded_stack(frames).contains("test_merge_diff"))
+                .map(|(_, rec)| rec.alloc_bytes)
+                .sum()
+        };
+
+        let merged = HeapReport::merge([&report, &report]);
+        assert_eq!(bytes(&merged), 2 << 20);
+        assert_eq!(merged.allocated().1, 2 * report.allocated().1);
+
+        let empty = HeapReport::merge(std::iter::empty::<&HeapReport>());
+        let diff = empty.diff(&merged);
+        assert_eq!(bytes(&diff), -(2 << 20));
+        assert_eq!(bytes(&merged.diff(&report)), 1 << 20);
+
+        // signed values go through the writers.
+        let mut folded = vec![];
+        diff.write_folded(&mut folded, collector::Metric::AllocBytes)
+            .unwrap();
+        assert!(String::from_utf8(folded)
+            .unwrap()
+            .lines()
+            .any(|line| line.contains("test_merge_diff") && line.ends_with(" -2097152")));
+        let proto = diff.pprof();
+        assert!(proto.sample.iter().any(|sample| sample.value[1] < 0));
+    }
+
+    #[cfg(feature = "enable_heap_profiler")]
+    #[test]
+    fn test_samples() {
+        let report = profile(|| {
+            std::hint::black_box(vec![0u8; 1 << 20]);
+        });
+
+        let sample = report
+            .samples()
+            // with measure_free, the free of the vector makes a sample of its own.
+            .find(|sample| {
+                sample.record().alloc_objects > 0
+                    && sample
+                        .frames()
+                        .any(|frame| frame.function.contains("test_samples"))
+            })
+            .unwrap();
+        assert_eq!(sample.record().alloc_bytes, 1 << 20);
+        assert_eq!(sample.record().alloc_objects, 1);
+        assert!(!sample
+            .frames()
+            .any(|frame| frame.function.contains("::Profiler::track_")));
+        assert_eq!(sample.thread(), Some(current_thread_id()));
+        assert_eq!(
+            sample.labels().collect::<Vec<_>>(),
+            vec![("alloc_size_1MiB".to_string(), 1)]
+        );
+
+        let totals = report.totals();
+        assert_eq!(totals.period, 1);
+        assert!(totals.sampled.alloc_bytes >= 1 << 20);
+        assert_eq!(
+            totals.sampled.alloc_bytes,
+            report
+                .samples()
+                .map(|s| s.record().alloc_bytes)
+                .sum::<isize>()
+        );
+        assert!(totals.allocated_bytes >= totals.sampled.alloc_bytes);
+    }
+
+    #[cfg(feature = "enable_heap_profiler")]
+    #[test]
+    fn test_samples_threads() {
+        #[inline(never)]
+        fn allocate() {
+            std::hint::black_box(vec![0u8; 1 << 20]);
+        }
+
+        let report = profile(|| {
+            allocate();
+            for _ in 0..2 {
+                std::thread::spawn(allocate).join().unwrap();
+            }
+        });
+
+        let mut samples = report
+            .samples()
+            .filter(|sample| {
+                sample.record().alloc_objects > 0
+                    && sample
+                        .frames()
+                        .any(|frame| frame.function.ends_with("test_samples_threads::allocate"))
+            })
+            .map(|sample| (sample.record().alloc_objects, sample.thread()))
+            .collect::<Vec<_>>();
+        samples.sort();
+        // the spawned threads allocate with the same stack, which makes a single call site.
+        assert_eq!(samples, vec![(1, Some(current_thread_id())), (2, None)]);
+    }
 }
@@ -0,0 +1,76 @@
+//! Programmatic access to the content of a [`HeapReport`](crate::HeapReport), see
+//! [`HeapReport::samples`](crate::HeapReport::samples) and [`HeapReport::totals`](crate::HeapReport::totals).
+
+use std::path::Path;
+
+use crate::collector::{MemProfileRecord, SampleThreads};
+use crate::profiler::{size_class_label, stack_symbols};
+
+/// The sampled allocations made at one call site.
+#[derive(Debug, Clone, Copy)]
+pub struct HeapSample<'a> {
+    frames: &'a pprof::Frames,
+    record: &'a MemProfileRecord,
+}
+
+impl<'a> HeapSample<'a> {
+    pub(crate) fn new(frames: &'a pprof::Frames, record: &'a MemProfileRecord) -> Self {
+        Self { frames, record }
+    }
+
+    /// The symbolized stack of the call site, innermost frame first, without the frames of the profiler itself.
+    /// Inlined functions get a frame of their own.
+    pub fn frames(&self) -> impl Iterator<Item = StackFrame<'a>> {
+        stack_symbols(self.frames)
+            .into_iter()
+            .map(|symbol| StackFrame {
+                function: symbol.name(),
+                file: symbol.filename.as_deref(),
+                line: symbol.lineno,
+            })
+    }
+
+    /// The thread the allocations were made by, see [`current_thread_id`](crate::current_thread_id), if they were all
+    /// made by the same one. See [`MemProfileRecord::threads`].
+    pub fn thread(&self) -> Option<u64> {
+        match self.record.threads {
+            SampleThreads::One(thread_id) => Some(thread_id),
+            _ => None,
+        }
+    }
+
+    /// The labels attached to the sample in the pprof output: the number of objects allocated in each non-empty size
+    /// class, keyed by `alloc_size_<lower bound>`, e.g. `alloc_size_64B`.
+    pub fn labels(&self) -> impl Iterator<Item = (String, isize)> + 'a {
+        self.record
+            .alloc_sizes
+            .iter()
+            .map(|(class, count)| (size_class_label(class), count))
+    }
+
+    pub fn record(&self) -> &'a MemProfileRecord {
+        self.record
+    }
+}
+
+/// A frame of the stack of a [`HeapSample`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StackFrame<'a> {
+    /// The demangled name of the function.
+    pub function: String,
+    /// The source file and line, when the binary has debug information.
+    pub file: Option<&'a Path>,
+    pub line: Option<u32>,
+}
+
+/// The totals of a [`HeapReport`](crate::HeapReport).
+#[derive(Debug, Clone, Default)]
+pub struct HeapTotals {
+    /// The sum of the records of all the samples.
+    pub sampled: MemProfileRecord,
+    /// The objects and bytes allocated during the session, including the ones that weren't sampled.
+    pub allocated_objects: isize,
+    pub allocated_bytes: isize,
+    /// The sampling period, in bytes.
+    pub period: usize,
+}
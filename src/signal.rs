@@ -0,0 +1,122 @@
+//! Dump the heap profile of a running process when it receives `SIGUSR2`.
+//!
+//! Taking a report allocates and symbolizes, neither of which is allowed in a signal handler: the handler only raises
+//! a flag, which a dedicated thread polls to write the report.
+
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::dumper::write_atomically;
+use crate::profiler::Profiler;
+use crate::HeapReport;
+
+// How often the dumping thread checks whether a signal has been received.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+static SIGNALED: AtomicBool = AtomicBool::new(false);
+static DUMP_PATH: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+// What `dump_on_sigusr2` has set up so far.
+struct InstallState {
+    thread_spawned: bool,
+    handler_installed: bool,
+}
+
+static INSTALL_STATE: Mutex<InstallState> = Mutex::new(InstallState {
+    thread_spawned: false,
+    handler_installed: false,
+});
+
+/// Writes a snapshot of the running profiler, in pprof format, to `path` every time the process receives `SIGUSR2`.
+/// Calling it again changes the path, or retries the installation if it failed. Signals received while no profiler is
+/// running are reported on stderr.
+pub fn dump_on_sigusr2<P: Into<PathBuf>>(path: P) -> std::io::Result<()> {
+    *DUMP_PATH.lock().unwrap_or_else(|e| e.into_inner()) = Some(path.into());
+
+    let mut state = INSTALL_STATE.lock().unwrap_or_else(|e| e.into_inner());
+    if !state.thread_spawned {
+        std::thread::Builder::new()
+            .name("heappy-signal".to_string())
+            .spawn(run)?;
+        state.thread_spawned = true;
+    }
+    if !state.handler_installed {
+        install_handler()?;
+        state.handler_installed = true;
+    }
+    Ok(())
+}
+
+fn install_handler() -> std::io::Result<()> {
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        action.sa_flags = libc::SA_RESTART;
+        libc::sigemptyset(&mut action.sa_mask);
+        if libc::sigaction(libc::SIGUSR2, &action, std::ptr::null_mut()) != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+extern "C" fn on_signal(_: libc::c_int) {
+    SIGNALED.store(true, Ordering::SeqCst);
+}
+
+fn run() {
+    loop {
+        std::thread::sleep(POLL_INTERVAL);
+        if !SIGNALED.swap(false, Ordering::SeqCst) {
+            continue;
+        }
+        let Some(path) = DUMP_PATH.lock().unwrap_or_else(|e| e.into_inner()).clone() else {
+            continue;
+        };
+        if !Profiler::enabled() {
+            eprintln!("heappy: received SIGUSR2 but the heap profiler is not running");
+            continue;
+        }
+
+        let report = HeapReport::snapshot();
+        if let Err(err) = write_atomically(&path, |file| report.write_pprof(file)) {
+            eprintln!(
+                "heappy: cannot write heap profile to {}: {}",
+                path.display(),
+                err
+            );
+        }
+    }
+}
+
+#[cfg(all(test, feature = "enable_heap_profiler"))]
+mod test {
+    use super::*;
+    use crate::profiler::test::{serial, test_profiler};
+
+    #[test]
+    fn test_dump_on_sigusr2() {
+        let _serial = serial();
+        let path = std::env::temp_dir().join(format!("heappy-sigusr2-{}.pb", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        dump_on_sigusr2(&path).unwrap();
+
+        let guard = test_profiler().build().unwrap();
+        std::hint::black_box(vec![0u8; 1 << 20]);
+        unsafe { libc::raise(libc::SIGUSR2) };
+
+        let deadline = std::time::Instant::now() + Duration::from_secs(10);
+        while !path.exists() {
+            assert!(std::time::Instant::now() < deadline, "timed out");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        drop(guard);
+
+        let buf = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let proto: pprof::protos::Profile = pprof::protos::Message::decode(buf.as_slice()).unwrap();
+        assert!(!proto.sample.is_empty());
+    }
+}
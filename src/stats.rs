@@ -0,0 +1,130 @@
+//! Process-wide and per-thread allocation counters, maintained by the allocator hooks whether a profiler is running
+//! or not.
+
+use std::cell::Cell;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+static ALLOCATED_BYTES: AtomicU64 = AtomicU64::new(0);
+static FREES: AtomicU64 = AtomicU64::new(0);
+static FREED_BYTES: AtomicU64 = AtomicU64::new(0);
+
+thread_local! {
+    // const initialized so that the hooks never allocate to access them.
+    static THREAD_ALLOCATIONS: Cell<u64> = const { Cell::new(0) };
+    static THREAD_ALLOCATED_BYTES: Cell<u64> = const { Cell::new(0) };
+    static THREAD_FREES: Cell<u64> = const { Cell::new(0) };
+    static THREAD_FREED_BYTES: Cell<u64> = const { Cell::new(0) };
+}
+
+/// Allocation counters, in usable bytes as reported by the allocator. See [`stats`] and [`thread_stats`].
+///
+/// The counters are only maintained when heappy's allocator hooks are compiled in (the `enable_heap_profiler`
+/// feature), otherwise they are always zero.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct AllocationStats {
+    pub allocations: u64,
+    pub allocated_bytes: u64,
+    pub frees: u64,
+    pub freed_bytes: u64,
+}
+
+impl AllocationStats {
+    /// Objects allocated and not yet freed. For a single thread this can be negative, since memory can be freed by
+    /// a thread other than the one which allocated it.
+    pub fn live_objects(&self) -> i64 {
+        self.allocations as i64 - self.frees as i64
+    }
+
+    /// Bytes allocated and not yet freed. Like [`AllocationStats::live_objects`], this can be negative for a thread.
+    pub fn live_bytes(&self) -> i64 {
+        self.allocated_bytes as i64 - self.freed_bytes as i64
+    }
+}
+
+/// Returns the allocation counters of the whole process since it started.
+pub fn stats() -> AllocationStats {
+    AllocationStats {
+        allocations: ALLOCATIONS.load(Ordering::Relaxed),
+        allocated_bytes: ALLOCATED_BYTES.load(Ordering::Relaxed),
+        frees: FREES.load(Ordering::Relaxed),
+        freed_bytes: FREED_BYTES.load(Ordering::Relaxed),
+    }
+}
+
+/// Returns the allocation counters of the current thread since it started.
+pub fn thread_stats() -> AllocationStats {
+    AllocationStats {
+        allocations: get(&THREAD_ALLOCATIONS),
+        allocated_bytes: get(&THREAD_ALLOCATED_BYTES),
+        frees: get(&THREAD_FREES),
+        freed_bytes: get(&THREAD_FREED_BYTES),
+    }
+}
+
+// Called by the allocation hooks for every successful allocation of `size` usable bytes.
+#[cfg_attr(not(feature = "enable_heap_profiler"), allow(dead_code))]
+pub(crate) fn track_allocated(size: usize) {
+    ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+    ALLOCATED_BYTES.fetch_add(size as u64, Ordering::Relaxed);
+    add(&THREAD_ALLOCATIONS, 1);
+    add(&THREAD_ALLOCATED_BYTES, size as u64);
+}
+
+// Called by the free hook for every non-null pointer of `size` usable bytes.
+#[cfg_attr(not(feature = "enable_heap_profiler"), allow(dead_code))]
+pub(crate) fn track_freed(size: usize) {
+    FREES.fetch_add(1, Ordering::Relaxed);
+    FREED_BYTES.fetch_add(size as u64, Ordering::Relaxed);
+    add(&THREAD_FREES, 1);
+    add(&THREAD_FREED_BYTES, size as u64);
+}
+
+type Counter = std::thread::LocalKey<Cell<u64>>;
+
+fn get(counter: &'static Counter) -> u64 {
+    counter.try_with(|c| c.get()).unwrap_or(0)
+}
+
+// The thread locals are gone while the thread is being torn down; allocations performed by the destructors of other
+// thread locals are then only accounted for process-wide.
+fn add(counter: &'static Counter, n: u64) {
+    let _ = counter.try_with(|c| c.set(c.get() + n));
+}
+
+#[cfg(all(test, feature = "enable_heap_profiler"))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_stats() {
+        let (before, thread_before) = (stats(), thread_stats());
+        let v = std::hint::black_box(vec![0u8; 1 << 20]);
+        let (during, thread_during) = (stats(), thread_stats());
+        drop(v);
+        let thread_after = thread_stats();
+
+        assert!(during.allocations > before.allocations);
+        assert!(during.allocated_bytes >= before.allocated_bytes + (1 << 20));
+        assert_eq!(thread_during.allocations, thread_before.allocations + 1);
+        assert_eq!(
+            thread_during.live_bytes() - thread_before.live_bytes(),
+            1 << 20
+        );
+        assert_eq!(thread_after.frees, thread_during.frees + 1);
+        assert_eq!(thread_after.live_bytes(), thread_before.live_bytes());
+    }
+
+    #[test]
+    fn test_stats_zero_sized_realloc() {
+        let before = thread_stats();
+        unsafe {
+            let ptr = libc::malloc(1 << 20);
+            assert!(libc::realloc(ptr, 0).is_null());
+        }
+        let after = thread_stats();
+
+        assert_eq!(after.frees, before.frees + 1);
+        assert_eq!(after.live_bytes(), before.live_bytes());
+    }
+}